@@ -1,10 +1,11 @@
 use crate::bittorrent::{AnnounceResponse, ScrapeFile, ScrapeResponse};
-use bendy::encoding::{Error, SingleItemEncoder, ToBencode};
+use bendy::encoding::{AsString, Error, SingleItemEncoder, ToBencode};
 
 impl ToBencode for ScrapeFile {
     const MAX_DEPTH: usize = 1;
 
     // bendy's emit methods return a result, which isn't immediately clear
+    #[allow(clippy::needless_borrows_for_generic_args)]
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
         encoder.emit_dict(|mut e| {
             e.emit_pair(b"complete", &self.complete)?;
@@ -25,6 +26,7 @@ impl ToBencode for ScrapeFile {
 impl ToBencode for AnnounceResponse {
     const MAX_DEPTH: usize = 5;
 
+    #[allow(clippy::needless_borrows_for_generic_args)]
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
         // If there is a failure reason present,
         // then nothing else gets encoded
@@ -33,6 +35,10 @@ impl ToBencode for AnnounceResponse {
                 encoder.emit_dict(|mut e| {
                     e.emit_pair(b"failure_reason", reason)?;
 
+                    if let Some(retry_in) = self.retry_in {
+                        e.emit_pair(b"retry in", retry_in)?;
+                    }
+
                     Ok(())
                 })?;
             }
@@ -40,15 +46,38 @@ impl ToBencode for AnnounceResponse {
             None => {
                 encoder.emit_dict(|mut e| {
                     e.emit_pair(b"complete", &self.complete)?;
+
+                    if self.crypto_flags {
+                        e.emit_pair(b"crypto_flags", AsString(self.crypto_flags_as_bytes()))?;
+                    }
+
+                    if let Some(external_ip) = self.external_ip_as_bytes() {
+                        e.emit_pair(b"external ip", AsString(external_ip))?;
+                    }
+
                     e.emit_pair(b"incomplete", &self.incomplete)?;
                     e.emit_pair(b"interval", &self.interval)?;
 
+                    // BEP 08: clients that sent 'sha_ih' get the peer
+                    // strings encrypted, along with the IV to decrypt them
+                    if let Some(obfuscated) = &self.obfuscated {
+                        e.emit_pair(b"iv", AsString(&obfuscated.iv))?;
+                    }
+
                     if let Some(min_interval) = &self.min_interval {
                         e.emit_pair(b"min_interval", min_interval)?;
                     }
 
-                    e.emit_pair(b"peers", &self.peersv4_as_compact())?;
-                    e.emit_pair(b"peers6", &self.peersv6_as_compact())?;
+                    match &self.obfuscated {
+                        Some(obfuscated) => {
+                            e.emit_pair(b"peers", AsString(&obfuscated.peers))?;
+                            e.emit_pair(b"peers6", AsString(&obfuscated.peers6))?;
+                        }
+                        None => {
+                            e.emit_pair(b"peers", &self.peersv4_as_compact())?;
+                            e.emit_pair(b"peers6", &self.peersv6_as_compact())?;
+                        }
+                    }
                     e.emit_pair(b"tracker_id", &self.tracker_id)?;
 
                     Ok(())
@@ -100,18 +129,20 @@ pub fn encode_scrape_response(response: ScrapeResponse) -> Vec<u8> {
 mod tests {
     use super::*;
     use crate::bittorrent::{AnnounceResponse, CompactPeerv4, CompactPeerv6, ScrapeResponse};
-    use std::net::{Ipv4Addr, Ipv6Addr};
-    use std::time::Instant;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn announce_response_encoding() {
         let peerv4_1 = CompactPeerv4 {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            requires_crypto: false,
         };
         let peerv4_2 = CompactPeerv4 {
             ip: Ipv4Addr::BROADCAST,
             port: 6894,
+            requires_crypto: false,
         };
 
         let mut peers: Vec<CompactPeerv4> = Vec::new();
@@ -123,12 +154,14 @@ mod tests {
                 0x2001, 0x0db8, 0x85a3, 0x0000, 0x0000, 0x8a2e, 0x0370, 0x7334,
             ),
             port: 6681,
+            requires_crypto: false,
         };
         let peerv6_2 = CompactPeerv6 {
             ip: Ipv6Addr::new(
                 0xfe80, 0x0000, 0x0000, 0x0000, 0x0202, 0xb3ff, 0xfe1e, 0x8329,
             ),
             port: 6699,
+            requires_crypto: false,
         };
 
         let mut peers6: Vec<CompactPeerv6> = Vec::new();
@@ -142,6 +175,74 @@ mod tests {
         assert_eq!(encoded.as_slice(), &b"d8:completei100e10:incompletei23e8:intervali60e5:peersli127ei0ei0ei1ei26ei237ei255ei255ei255ei255ei26ei238ee6:peers6li32ei1ei13ei184ei133ei163ei0ei0ei0ei0ei138ei46ei3ei112ei115ei52ei26ei25ei254ei128ei0ei0ei0ei0ei0ei0ei2ei2ei179ei255ei254ei30ei131ei41ei26ei43ee10:tracker_id0:e"[..]);
     }
 
+    #[test]
+    fn announce_response_external_ip_encoding() {
+        let mut response = AnnounceResponse::new(60, 1, 2, Vec::new(), Vec::new()).unwrap();
+        response.external_ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+        let encoded = encode_announce_response(response);
+
+        assert_eq!(encoded.as_slice(), &b"d8:completei1e11:external ip4:\x0a\x00\x00\x0110:incompletei2e8:intervali60e5:peersle6:peers6le10:tracker_id0:e"[..]);
+    }
+
+    #[test]
+    fn announce_response_obfuscated_encoding() {
+        use crate::obfuscation::ObfuscatedPeers;
+        use bendy::decoding::{Decoder, Object};
+
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0";
+        let peers = vec![CompactPeerv4 {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            requires_crypto: false,
+        }];
+        let peers6 = vec![CompactPeerv6 {
+            ip: Ipv6Addr::LOCALHOST,
+            port: 6681,
+            requires_crypto: false,
+        }];
+
+        let mut response = AnnounceResponse::new(60, 1, 2, peers, peers6).unwrap();
+        let compact = response.peersv4_as_compact();
+        let compact6 = response.peersv6_as_compact();
+        response.obfuscated = Some(ObfuscatedPeers::new(
+            info_hash,
+            compact.clone(),
+            compact6.clone(),
+        ));
+
+        let encoded = encode_announce_response(response);
+
+        let mut iv = Vec::new();
+        let mut encrypted = Vec::new();
+        let mut encrypted6 = Vec::new();
+        let mut decoder = Decoder::new(&encoded);
+        match decoder.next_object().unwrap() {
+            Some(Object::Dict(mut dict)) => {
+                while let Some((key, value)) = dict.next_pair().unwrap() {
+                    let target = match key {
+                        b"iv" => &mut iv,
+                        b"peers" => &mut encrypted,
+                        b"peers6" => &mut encrypted6,
+                        _ => continue,
+                    };
+                    match value {
+                        Object::Bytes(bytes) => target.extend_from_slice(bytes),
+                        _ => panic!("{} is not a string", String::from_utf8_lossy(key)),
+                    }
+                }
+            }
+            _ => panic!("response is not a dictionary"),
+        }
+
+        assert_eq!(iv.len(), 20);
+        assert_ne!(encrypted, compact);
+
+        let decrypted = ObfuscatedPeers::with_iv(info_hash, iv, encrypted, encrypted6);
+        assert_eq!(decrypted.peers, compact);
+        assert_eq!(decrypted.peers6, compact6);
+    }
+
     #[test]
     fn announce_failure_encoding() {
         let failure_reason = "ouch".to_string();
@@ -152,6 +253,17 @@ mod tests {
         assert_eq!(encoded.as_slice(), b"d14:failure_reason4:ouche");
     }
 
+    #[test]
+    fn announce_retry_encoding() {
+        let failure = AnnounceResponse::retry_later("slow down".to_string(), 30);
+        let encoded = encode_announce_response(failure);
+
+        assert_eq!(
+            encoded.as_slice(),
+            &b"d14:failure_reason9:slow down8:retry ini30ee"[..]
+        );
+    }
+
     #[test]
     fn scrape_response_encoding() {
         let file1 = ScrapeFile {
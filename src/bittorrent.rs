@@ -9,15 +9,36 @@ use std::time::Instant;
 
 use bytes::BufMut;
 use percent_encoding;
-use regex::Regex;
+use serde::{Deserialize, Serialize};
 use url::form_urlencoded;
 
+use crate::obfuscation::ObfuscatedPeers;
 use crate::util::{string_to_event, Event};
 
 trait Compact {
     fn compact(&self) -> Vec<u8>;
 }
 
+// Azureus-style message stream encryption, as announced through the
+// 'supportcrypto' and 'requirecrypto' parameters. A peer that requires
+// encryption will refuse any plaintext connection.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+pub enum Crypto {
+    None,
+    Supported,
+    Required,
+}
+
+impl Crypto {
+    // Two peers can only talk if their encryption preferences overlap
+    pub fn compatible_with(self, other: Crypto) -> bool {
+        !matches!(
+            (self, other),
+            (Crypto::Required, Crypto::None) | (Crypto::None, Crypto::Required)
+        )
+    }
+}
+
 // These two peer types could probably be implemented more elegantly
 // with a trait, but there's only two types right now, so it's not a lot of work
 #[derive(Clone, Eq, Ord, PartialOrd, Debug)]
@@ -26,6 +47,8 @@ pub struct Peerv4 {
     pub ip: Ipv4Addr,
     pub port: u16,
     pub last_announced: Instant,
+    pub crypto: Crypto,
+    pub crypto_port: Option<u16>,
 }
 
 #[derive(Clone, Eq, Ord, PartialOrd, Debug)]
@@ -34,6 +57,8 @@ pub struct Peerv6 {
     pub ip: Ipv6Addr,
     pub port: u16,
     pub last_announced: Instant,
+    pub crypto: Crypto,
+    pub crypto_port: Option<u16>,
 }
 
 impl Compact for Peerv4 {
@@ -70,12 +95,14 @@ impl Compact for Peerv6 {
 pub struct CompactPeerv4 {
     pub ip: Ipv4Addr,
     pub port: u16,
+    pub requires_crypto: bool,
 }
 
 #[derive(Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Debug)]
 pub struct CompactPeerv6 {
     pub ip: Ipv6Addr,
     pub port: u16,
+    pub requires_crypto: bool,
 }
 
 impl Compact for CompactPeerv4 {
@@ -105,6 +132,64 @@ impl Compact for CompactPeerv6 {
     }
 }
 
+impl Peer {
+    pub fn crypto(&self) -> Crypto {
+        match self {
+            Peer::V4(p) => p.crypto,
+            Peer::V6(p) => p.crypto,
+        }
+    }
+
+    pub fn with_ip(self, ip: IpAddr) -> Peer {
+        let (peer_id, port, last_announced, crypto, crypto_port) = match self {
+            Peer::V4(p) => (p.peer_id, p.port, p.last_announced, p.crypto, p.crypto_port),
+            Peer::V6(p) => (p.peer_id, p.port, p.last_announced, p.crypto, p.crypto_port),
+        };
+
+        match ip {
+            IpAddr::V4(ip) => Peer::V4(Peerv4 {
+                peer_id,
+                ip,
+                port,
+                last_announced,
+                crypto,
+                crypto_port,
+            }),
+            IpAddr::V6(ip) => Peer::V6(Peerv6 {
+                peer_id,
+                ip,
+                port,
+                last_announced,
+                crypto,
+                crypto_port,
+            }),
+        }
+    }
+
+    // Peers that listen for encrypted connections on a separate port
+    // are handed out with that port, but only to requesters that can
+    // encrypt; everyone else gets the port for plain connections
+    pub fn to_compact(&self, requester: Crypto) -> CompactPeer {
+        let port = |port: u16, crypto_port: Option<u16>| match requester {
+            Crypto::None => port,
+            Crypto::Supported | Crypto::Required => crypto_port.unwrap_or(port),
+        };
+
+        match self {
+            Peer::V4(p) => CompactPeer::V4(CompactPeerv4 {
+                ip: p.ip,
+                port: port(p.port, p.crypto_port),
+                requires_crypto: p.crypto == Crypto::Required,
+            }),
+            Peer::V6(p) => CompactPeer::V6(CompactPeerv6 {
+                ip: p.ip,
+                port: port(p.port, p.crypto_port),
+                requires_crypto: p.crypto == Crypto::Required,
+            }),
+        }
+    }
+}
+
 /*
  * Proper peer hashing is important as the entire peer storage capability
  * depends upon it. Peer reaping requires a last_announced time, which
@@ -173,6 +258,7 @@ impl Compact for CompactPeer {
 #[derive(Debug)]
 pub struct AnnounceRequest {
     pub info_hash: String,
+    pub sha_ih: Option<Vec<u8>>,
     pub peer: Peer,
     pub port: u16,
     pub uploaded: u32,
@@ -181,16 +267,20 @@ pub struct AnnounceRequest {
     pub compact: bool,
     pub no_peer_id: bool,
     pub event: Event,
+    pub crypto: Crypto,
     pub ip: Option<IpAddr>,
+    pub remote_ip: Option<IpAddr>,
     pub numwant: Option<u32>,
     pub key: Option<String>,
     pub trackerid: Option<String>,
 }
 
 impl AnnounceRequest {
+    // Failures are handed back as a ready-to-encode response
+    #[allow(clippy::result_large_err)]
     pub fn new(
         url_string: &str,
-        req_ip: Option<&str>,
+        req_ip: Option<IpAddr>,
     ) -> Result<AnnounceRequest, AnnounceResponse> {
         let request_kv_pairs = form_urlencoded::parse(url_string.as_bytes()).into_owned();
 
@@ -203,6 +293,9 @@ impl AnnounceRequest {
         let mut compact = false;
         let mut no_peer_id = false;
         let mut event = Event::None;
+        let mut support_crypto = false;
+        let mut require_crypto = false;
+        let mut crypto_port = None;
         let mut ip = None;
         let mut numwant = None;
         let mut key = None;
@@ -246,6 +339,13 @@ impl AnnounceRequest {
                     _ => return Err(AnnounceResponse::failure("Malformed request".to_string())),
                 },
                 "event" => event = string_to_event(value),
+                "supportcrypto" => support_crypto = value == "1",
+                "requirecrypto" => require_crypto = value == "1",
+                "cryptoport" => match value.parse::<u16>() {
+                    Ok(n) if n != 0 => crypto_port = Some(n),
+                    Ok(_) => {}
+                    _ => return Err(AnnounceResponse::failure("Malformed request".to_string())),
+                },
                 "ip" => match value.parse::<IpAddr>() {
                     Ok(addr) => ip = Some(addr),
                     _ => return Err(AnnounceResponse::failure("Malformed request".to_string())),
@@ -260,43 +360,57 @@ impl AnnounceRequest {
             }
         }
 
+        // BEP 08: the obfuscated hash is binary, so it has to be decoded
+        // from the raw query string; it is resolved against the store later
+        let sha_ih = raw_param(url_string, "sha_ih");
+        if let Some(hash) = &sha_ih {
+            if hash.len() != 20 {
+                return Err(AnnounceResponse::failure("Malformed request".to_string()));
+            }
+        }
+
         // This should not be the default value
-        if info_hash == "" {
+        if info_hash.is_empty() && sha_ih.is_none() {
             return Err(AnnounceResponse::failure("Malformed request".to_string()));
         }
 
-        // Digusting unwrap sequence, but whatever.
-        if ip.is_none() {
-            if let Some(addr) = req_ip {
-                if addr.starts_with('[') {
-                    let re = Regex::new(r"\[(.*)\]").unwrap();
-                    let caps = re.captures(addr).unwrap();
-                    let ip_string = &caps[0];
-                    ip = Some(ip_string.parse().unwrap());
-                } else {
-                    let ip_string: Vec<&str> = addr.split(':').collect();
-                    ip = Some(ip_string[0].parse().unwrap());
-                }
-            }
-        }
+        // The address the request actually came from is kept separately
+        // from the client-supplied one so that it can be echoed back,
+        // and so that the override can be checked against policy later
+        let remote_ip = req_ip;
+
+        let crypto = if require_crypto {
+            Crypto::Required
+        } else if support_crypto {
+            Crypto::Supported
+        } else {
+            Crypto::None
+        };
 
-        let peer = match ip.unwrap() {
-            IpAddr::V4(i) => Peer::V4(Peerv4 {
+        let peer = match ip.or(remote_ip) {
+            Some(IpAddr::V4(i)) => Peer::V4(Peerv4 {
                 peer_id: peer_string,
                 ip: i,
                 port,
                 last_announced: Instant::now(),
+                crypto,
+                crypto_port,
             }),
-            IpAddr::V6(i) => Peer::V6(Peerv6 {
+            Some(IpAddr::V6(i)) => Peer::V6(Peerv6 {
                 peer_id: peer_string,
                 ip: i,
                 port,
                 last_announced: Instant::now(),
+                crypto,
+                crypto_port,
             }),
+            // Without any address, the peer can't be reached anyways
+            None => return Err(AnnounceResponse::failure("Malformed request".to_string())),
         };
 
         Ok(AnnounceRequest {
             info_hash,
+            sha_ih,
             peer,
             port,
             uploaded,
@@ -305,12 +419,38 @@ impl AnnounceRequest {
             compact,
             no_peer_id,
             event,
+            crypto,
             ip,
+            remote_ip,
             numwant,
             key,
             trackerid,
         })
     }
+
+    // Registers the peer under the address the request came from,
+    // discarding whatever address the client asked to be registered as
+    pub fn discard_ip_override(&mut self) -> bool {
+        match self.remote_ip {
+            Some(remote_ip) => {
+                self.ip = None;
+                self.peer = self.peer.clone().with_ip(remote_ip);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub fn raw_param(url_string: &str, name: &str) -> Option<Vec<u8>> {
+    url_string.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next()? == name {
+            Some(percent_encoding::percent_decode(kv.next().unwrap_or("").as_bytes()).collect())
+        } else {
+            None
+        }
+    })
 }
 
 // Peer types are functionally the same, but due to different
@@ -318,6 +458,7 @@ impl AnnounceRequest {
 #[derive(Default, Debug)]
 pub struct AnnounceResponse {
     pub failure_reason: Option<String>,
+    pub retry_in: Option<u64>,
     pub interval: u32,
     pub min_interval: Option<u32>,
     pub tracker_id: String,
@@ -325,6 +466,9 @@ pub struct AnnounceResponse {
     pub incomplete: u32,
     pub peers: Vec<CompactPeerv4>,
     pub peers6: Vec<CompactPeerv6>,
+    pub crypto_flags: bool,
+    pub external_ip: Option<IpAddr>,
+    pub obfuscated: Option<ObfuscatedPeers>,
 }
 
 impl AnnounceResponse {
@@ -337,6 +481,7 @@ impl AnnounceResponse {
     ) -> Result<AnnounceResponse, &'static str> {
         Ok(AnnounceResponse {
             failure_reason: None,
+            retry_in: None,
             interval,
             min_interval: None,
             tracker_id: "".to_string(),
@@ -344,6 +489,9 @@ impl AnnounceResponse {
             incomplete,
             peers,
             peers6,
+            crypto_flags: false,
+            external_ip: None,
+            obfuscated: None,
         })
     }
 
@@ -354,6 +502,15 @@ impl AnnounceResponse {
         }
     }
 
+    // BEP 31: tells clients how many seconds to wait before trying again
+    pub fn retry_later(reason: String, retry_in: u64) -> AnnounceResponse {
+        AnnounceResponse {
+            failure_reason: Some(reason),
+            retry_in: Some(retry_in),
+            ..Default::default()
+        }
+    }
+
     pub fn peersv4_as_compact(&self) -> Vec<u8> {
         let mut compact_peers = Vec::new();
         for peer in &self.peers {
@@ -369,6 +526,24 @@ impl AnnounceResponse {
         }
         compact_peers.concat()
     }
+
+    // One byte per entry in 'peers', set when that peer requires encryption.
+    // The Azureus extension predates BEP 07, so there is no IPv6 counterpart.
+    pub fn crypto_flags_as_bytes(&self) -> Vec<u8> {
+        self.peers
+            .iter()
+            .map(|peer| peer.requires_crypto as u8)
+            .collect()
+    }
+
+    // BEP 24: the external IP is sent as 4 or 16 raw bytes
+    pub fn external_ip_as_bytes(&self) -> Option<Vec<u8>> {
+        match self.external_ip {
+            Some(IpAddr::V4(ip)) => Some(ip.octets().to_vec()),
+            Some(IpAddr::V6(ip)) => Some(ip.octets().to_vec()),
+            None => None,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -411,6 +586,7 @@ pub struct ScrapeResponse {
 }
 
 impl ScrapeResponse {
+    #[allow(clippy::result_unit_err)]
     pub fn new() -> Result<ScrapeResponse, ()> {
         Ok(ScrapeResponse {
             failure_reason: None,
@@ -451,6 +627,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn announce_remote_ip_resolution() {
+        let url_string = "info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=ABCDEFGHIJKLMNOPQRST&port=6881";
+
+        let req = AnnounceRequest::new(url_string, Some("2001:db8::1".parse().unwrap())).unwrap();
+        assert_eq!(req.remote_ip, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(req.ip, None);
+
+        let url_string = "info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=ABCDEFGHIJKLMNOPQRST&port=6881\
+             &ip=192.168.1.20";
+
+        let mut req = AnnounceRequest::new(url_string, Some("10.0.0.1".parse().unwrap())).unwrap();
+        assert_eq!(req.remote_ip, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(req.ip, Some("192.168.1.20".parse().unwrap()));
+        assert_eq!(
+            req.peer.to_compact(Crypto::None),
+            Peer::V4(Peerv4 {
+                peer_id: String::new(),
+                ip: "192.168.1.20".parse().unwrap(),
+                port: 6881,
+                last_announced: Instant::now(),
+                crypto: Crypto::None,
+                crypto_port: None,
+            })
+            .to_compact(Crypto::None)
+        );
+
+        assert!(req.discard_ip_override());
+        assert_eq!(req.ip, None);
+        match req.peer {
+            Peer::V4(p) => assert_eq!(p.ip, Ipv4Addr::new(10, 0, 0, 1)),
+            Peer::V6(_) => panic!("Peer should have been IPv4"),
+        }
+    }
+
+    #[test]
+    fn announce_crypto_parameters() {
+        let url_string = "info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=ABCDEFGHIJKLMNOPQRST&port=0\
+             &supportcrypto=1&requirecrypto=1&cryptoport=6881";
+
+        let req = AnnounceRequest::new(url_string, Some("10.0.0.1".parse().unwrap())).unwrap();
+        assert_eq!(req.crypto, Crypto::Required);
+        match req.peer {
+            Peer::V4(p) => assert_eq!(p.crypto_port, Some(6881)),
+            Peer::V6(_) => panic!("Peer should have been IPv4"),
+        }
+    }
+
+    #[test]
+    fn announce_obfuscated_request() {
+        let url_string = "sha_ih=%90%28%9F%D3M%FC%1C%F8%F3%16%A2h%AD%D85L%853DX\
+             &peer_id=ABCDEFGHIJKLMNOPQRST&port=6881";
+
+        let req = AnnounceRequest::new(url_string, Some("10.0.0.1".parse().unwrap())).unwrap();
+        assert!(req.info_hash.is_empty());
+        assert_eq!(
+            req.sha_ih.unwrap(),
+            b"\x90\x28\x9f\xd3M\xfc\x1c\xf8\xf3\x16\xa2h\xad\xd85L\x853DX".to_vec()
+        );
+    }
+
     #[test]
     fn announce_failure_return() {
         let failure_reason = "It's not you...no, it's just you".to_string();
@@ -462,14 +699,17 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn announce_response_creation() {
         let peerv4_1 = CompactPeerv4 {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            requires_crypto: false,
         };
         let peerv4_2 = CompactPeerv4 {
             ip: Ipv4Addr::BROADCAST,
             port: 6894,
+            requires_crypto: false,
         };
 
         let mut peers: Vec<CompactPeerv4> = Vec::new();
@@ -481,12 +721,14 @@ mod tests {
                 0x2001, 0x0db8, 0x85a3, 0x0000, 0x0000, 0x8a2e, 0x0370, 0x7334,
             ),
             port: 6681,
+            requires_crypto: false,
         };
         let peerv6_2 = CompactPeerv6 {
             ip: Ipv6Addr::new(
                 0xfe80, 0x0000, 0x0000, 0x0000, 0x0202, 0xb3ff, 0xfe1e, 0x8329,
             ),
             port: 6699,
+            requires_crypto: false,
         };
 
         let mut peers6: Vec<CompactPeerv6> = Vec::new();
@@ -505,6 +747,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6681,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         let mut localhost_port_byte_string = vec![];
@@ -517,6 +761,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn peerv6_compact_transform() {
         let peer = Peer::V6(Peerv6 {
             peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
@@ -525,6 +770,8 @@ mod tests {
             ),
             port: 6681,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         let mut localhost_port_byte_string = vec![];
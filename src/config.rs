@@ -1,49 +1,253 @@
 use std::fs::File;
 use std::io::Read;
+use std::str::FromStr;
 
+use log::LevelFilter;
+
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use toml;
+use toml::{Table, Value};
+
+use crate::network::cidr::Cidr;
+use crate::network::middleware::ClientApproval as ClientApprovalRules;
 
-#[derive(Default, Deserialize, Clone)]
+#[derive(Default, Deserialize, Clone, PartialEq)]
 pub struct Config {
     pub network: Network,
     pub storage: Storage,
     pub bt: BitTorrent,
     pub client_approval: ClientApproval,
+    #[serde(default)]
+    pub interval: Interval,
+    #[serde(default)]
+    pub bans: Bans,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub reload: Reload,
+    #[serde(default)]
+    pub snapshot: Snapshot,
+    #[serde(default)]
+    pub shutdown: Shutdown,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct Network {
     pub binding: String,
+    pub tls_binding: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_reload_interval: u64,
+    pub trusted_proxies: Vec<Cidr>,
+    pub proxy_protocol: bool,
+    pub ip_override: IpOverride,
+    pub ip_override_networks: Vec<Cidr>,
+}
+
+// Decides when the 'ip' announce parameter may stand in for the
+// address the request came from. Anything else is rejected and the
+// peer is registered under its real address instead.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpOverride {
+    // Any client may register any address
+    Allow,
+    // The parameter is never honored
+    Ignore,
+    // Only honored from trusted proxies or 'ip_override_networks'
+    Trusted,
+    // Only private addresses, sent from within the same private network
+    SameNetwork,
 }
 
-#[derive(Deserialize, Clone)]
+// The password can also be kept out of the file, in
+// 'password_file' or the TYTO_STORAGE__PASSWORD variable
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct Storage {
     pub backend: String,
     pub path: String,
     pub password: Option<String>,
+    pub password_file: Option<String>,
+    pub timeout: u64,
+    pub connect_retries: u32,
+    pub retry_backoff: u64,
+    pub max_backoff: u64,
+    pub degraded_start: bool,
 }
 
-#[derive(Deserialize, Clone)]
+impl Storage {
+    pub fn password(&self) -> Result<Option<String>, String> {
+        match (&self.password, &self.password_file) {
+            (Some(password), None) => Ok(Some(password.clone())),
+            (None, Some(file)) => std::fs::read_to_string(file)
+                .map(|password| Some(password.trim_end_matches(&['\r', '\n'][..]).to_string()))
+                .map_err(|e| format!("Could not read password file {}: {}", file, e)),
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => {
+                Err("Only one of storage.password and storage.password_file may be set".to_string())
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct BitTorrent {
     pub announce_rate: u64,
     pub peer_timeout: u64,
     pub reap_interval: u64,
     pub flush_interval: u64,
+    pub flush_batch_size: usize,
+    pub external_ip: bool,
+    pub default_numwant: u32,
+    pub max_numwant: u32,
+}
+
+// Announce intervals scale with swarm size between 'min' and 'max'
+// seconds, and are raised further once the tracker handles more
+// than 'load_threshold' requests per second.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Interval {
+    pub adaptive: bool,
+    pub min: u64,
+    pub max: u64,
+    pub small_swarm: u32,
+    pub large_swarm: u32,
+    pub load_threshold: f64,
+    pub jitter: f64,
+}
+
+// Bans are read from 'file', from the 'bans' table of the storage
+// backend if 'database' is set, or from both, and are reloaded
+// every 'reload_interval' seconds.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Bans {
+    pub file: Option<String>,
+    pub database: bool,
+    pub reload_interval: u64,
+}
+
+impl Bans {
+    pub fn enabled(&self) -> bool {
+        self.file.is_some() || self.database
+    }
+}
+
+// Each route has its own token bucket per client address (or per /64
+// for IPv6). Buckets hold up to 'burst' requests and refill at 'rate'
+// requests per second. At most 'max_clients' addresses are tracked
+// per route; the least recently seen are forgotten first.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimit {
+    pub enabled: bool,
+    pub max_clients: usize,
+    pub announce: Limit,
+    pub scrape: Limit,
+    pub stats: Limit,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Log {
+    pub level: String,
+}
+
+// The configuration file is reloaded on SIGHUP, and also whenever
+// it changes on disk if 'watch' is set, checking every
+// 'watch_interval' seconds.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Reload {
+    pub watch: bool,
+    pub watch_interval: u64,
 }
 
-#[derive(Deserialize, Clone)]
+// Swarms are written to 'path' every 'interval' seconds and when the
+// tracker shuts down, so that they can be restored on the next start
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Snapshot {
+    pub path: Option<String>,
+    pub interval: u64,
+}
+
+// On SIGTERM or SIGINT, requests that are being handled get up to
+// 'drain_timeout' seconds to finish. Torrents are then flushed and the
+// swarms saved, which may take up to 'persist_timeout' seconds.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Shutdown {
+    pub drain_timeout: u64,
+    pub persist_timeout: u64,
+}
+
+// Client approval only makes sense for announces, which carry a peer ID.
+// Scrapes and statistics are instead restricted by address and token.
+#[derive(Default, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Access {
+    pub scrape: RouteAccess,
+    pub stats: RouteAccess,
+}
+
+// An empty 'allow' list admits every address. If a 'token' is set,
+// it has to be sent as a bearer token or as the 'token' parameter.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RouteAccess {
+    pub enabled: bool,
+    pub allow: Vec<Cidr>,
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct ClientApproval {
     pub enabled: bool,
     pub blacklist_style: bool,
     pub versioned: bool,
     pub client_list: Vec<String>,
+    pub shadow: bool,
+    pub message: Option<String>,
+    pub rules: Vec<ClientRule>,
+}
+
+// A rule matches either a decoded client and an optional version
+// range, e.g. 'qB >= 4.2', or a regex 'pattern' on the raw peer ID
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ClientRule {
+    pub client: Option<String>,
+    pub pattern: Option<String>,
+    pub message: Option<String>,
 }
 
 impl Default for Network {
     fn default() -> Self {
         Network {
             binding: "0.0.0.0:8585".to_string(),
+            tls_binding: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_reload_interval: 300,
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            ip_override: IpOverride::Ignore,
+            ip_override_networks: Vec::new(),
         }
     }
 }
@@ -54,6 +258,12 @@ impl Default for Storage {
             backend: "memory".to_string(),
             path: "".to_string(),
             password: None,
+            password_file: None,
+            timeout: 30,
+            connect_retries: 5,
+            retry_backoff: 1,
+            max_backoff: 60,
+            degraded_start: false,
         }
     }
 }
@@ -65,10 +275,15 @@ impl Default for BitTorrent {
             peer_timeout: 7200,
             reap_interval: 1800,
             flush_interval: 900,
+            flush_batch_size: 1000,
+            external_ip: false,
+            default_numwant: 50,
+            max_numwant: 200,
         }
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for ClientApproval {
     fn default() -> ClientApproval {
         ClientApproval {
@@ -76,51 +291,608 @@ impl Default for ClientApproval {
             blacklist_style: false,
             versioned: false,
             client_list: Vec::new(),
+            shadow: false,
+            message: None,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Default for Interval {
+    fn default() -> Self {
+        Interval {
+            adaptive: false,
+            min: 900,
+            max: 3600,
+            small_swarm: 10,
+            large_swarm: 1000,
+            load_threshold: 5000.0,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl Default for Bans {
+    fn default() -> Self {
+        Bans {
+            file: None,
+            database: false,
+            reload_interval: 300,
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            enabled: false,
+            max_clients: 100_000,
+            announce: Limit {
+                rate: 1.0,
+                burst: 30,
+            },
+            scrape: Limit {
+                rate: 0.5,
+                burst: 10,
+            },
+            stats: Limit {
+                rate: 0.2,
+                burst: 5,
+            },
+        }
+    }
+}
+
+impl Default for RouteAccess {
+    fn default() -> Self {
+        RouteAccess {
+            enabled: true,
+            allow: Vec::new(),
+            token: None,
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            level: "info".to_string(),
         }
     }
 }
 
+impl Log {
+    pub fn level_filter(&self) -> Result<LevelFilter, String> {
+        LevelFilter::from_str(&self.level).map_err(|_| format!("Invalid log level: {}", self.level))
+    }
+}
+
+impl Default for Reload {
+    fn default() -> Self {
+        Reload {
+            watch: false,
+            watch_interval: 10,
+        }
+    }
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            path: None,
+            interval: 300,
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            drain_timeout: 30,
+            persist_timeout: 60,
+        }
+    }
+}
+
+// Moves a setting that can't change at runtime back to its current value
+fn keep<T: PartialEq + Clone>(
+    name: &'static str,
+    current: &T,
+    new: &mut T,
+    pending: &mut Vec<&'static str>,
+) {
+    if current != new {
+        pending.push(name);
+        *new = current.clone();
+    }
+}
+
 impl Config {
-    pub fn load_config(path: String) -> Config {
+    // Reads a configuration file and applies any environment overrides.
+    // Every invalid value is reported at once, rather than just the first.
+    // Unknown keys are usually typos, which fail the whole file in strict
+    // mode and are only warned about otherwise.
+    pub fn read(path: &str, strict: bool) -> Result<Config, String> {
         let mut config_toml = String::new();
 
-        let mut file = match File::open(&path) {
-            Ok(file) => file,
-            _ => {
-                error!("Could not find config file; loading default config...");
-                return Config::default();
+        let mut file =
+            File::open(path).map_err(|e| format!("Could not find config file {}: {}", path, e))?;
+        file.read_to_string(&mut config_toml)
+            .map_err(|e| format!("Could not read config file {}: {}", path, e))?;
+
+        let mut table = config_toml
+            .parse::<Table>()
+            .map_err(|e| format!("Could not parse config file {}: {}", path, e))?;
+        apply_overrides(&mut table, std::env::vars());
+
+        let (unknown, mut problems) = check(&table);
+        if strict {
+            problems.extend(unknown.iter().map(|key| format!("unknown key '{}'", key)));
+        } else {
+            for key in &unknown {
+                warn!("Ignoring unknown configuration key '{}'", key);
             }
-        };
+        }
 
-        if file.read_to_string(&mut config_toml).is_err() {
-            error!("Could not read config file; loading default config...");
-            return Config::default();
-        };
+        if problems.is_empty() {
+            match Config::deserialize(Value::Table(table)) {
+                Ok(config) => {
+                    problems.extend(config.validate());
+                    if problems.is_empty() {
+                        return Ok(config);
+                    }
+                }
+                Err(e) => problems.push(e.to_string().replace('\n', " ").trim().to_string()),
+            }
+        }
+
+        Err(format!(
+            "Invalid config file {}:\n  {}",
+            path,
+            problems.join("\n  ")
+        ))
+    }
 
-        let config: Config = match toml::from_str(&config_toml) {
-            Ok(t) => t,
-            _ => {
-                error!("Could not parse config file; loading default config...");
+    // Checks that go beyond what the types alone can express
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let Err(e) = self.log.level_filter() {
+            problems.push(e);
+        }
+        if self.network.tls_binding.is_some()
+            && (self.network.tls_cert.is_none() || self.network.tls_key.is_none())
+        {
+            problems.push("network.tls_binding requires both tls_cert and tls_key".to_string());
+        }
+        if self.storage.password.is_some() && self.storage.password_file.is_some() {
+            problems.push(
+                "only one of storage.password and storage.password_file may be set".to_string(),
+            );
+        }
+
+        let intervals = [
+            ("bt.announce_rate", self.bt.announce_rate),
+            ("bt.reap_interval", self.bt.reap_interval),
+            ("bt.flush_interval", self.bt.flush_interval),
+            ("bans.reload_interval", self.bans.reload_interval),
+            (
+                "network.tls_reload_interval",
+                self.network.tls_reload_interval,
+            ),
+            ("reload.watch_interval", self.reload.watch_interval),
+            ("snapshot.interval", self.snapshot.interval),
+            ("shutdown.persist_timeout", self.shutdown.persist_timeout),
+            ("storage.timeout", self.storage.timeout),
+            ("storage.retry_backoff", self.storage.retry_backoff),
+        ];
+        for (name, seconds) in intervals.iter() {
+            if *seconds == 0 {
+                problems.push(format!("{} must be at least one second", name));
+            }
+        }
+
+        if self.storage.retry_backoff > self.storage.max_backoff {
+            problems.push("storage.retry_backoff must not exceed storage.max_backoff".to_string());
+        }
+        if self.storage.degraded_start && self.snapshot.path.is_none() {
+            problems.push("storage.degraded_start needs a snapshot.path to start from".to_string());
+        }
+        if self.bt.flush_batch_size == 0 {
+            problems.push("bt.flush_batch_size must be at least one".to_string());
+        }
+        if self.bt.default_numwant > self.bt.max_numwant {
+            problems.push("bt.default_numwant must not exceed bt.max_numwant".to_string());
+        }
+        if self.interval.min > self.interval.max {
+            problems.push("interval.min must not exceed interval.max".to_string());
+        }
+        if self.interval.small_swarm >= self.interval.large_swarm {
+            problems.push("interval.small_swarm must be below interval.large_swarm".to_string());
+        }
+        if !(0.0..1.0).contains(&self.interval.jitter) {
+            problems.push("interval.jitter must be at least 0 and below 1".to_string());
+        }
+
+        let limits = [
+            ("rate_limit.announce", self.rate_limit.announce),
+            ("rate_limit.scrape", self.rate_limit.scrape),
+            ("rate_limit.stats", self.rate_limit.stats),
+        ];
+        for (name, limit) in limits.iter() {
+            if limit.rate.is_nan() || limit.rate < 0.0 || limit.burst == 0 {
+                problems.push(format!(
+                    "{} needs a non-negative rate and a burst of at least one",
+                    name
+                ));
+            }
+        }
+
+        if let Err(e) = ClientApprovalRules::new(&self.client_approval) {
+            problems.push(format!("client_approval: {}", e));
+        }
+
+        problems
+    }
+
+    // Takes on the settings of 'new' that are safe to change while the
+    // tracker is running. Listeners, storage, middleware that is set up
+    // when the server starts and the janitor's schedule keep their
+    // current values, and are returned by name so they can be reported.
+    pub fn reloaded(&self, mut new: Config) -> (Config, Vec<&'static str>) {
+        let mut pending = Vec::new();
+
+        keep("network", &self.network, &mut new.network, &mut pending);
+        keep("storage", &self.storage, &mut new.storage, &mut pending);
+        keep(
+            "rate_limit",
+            &self.rate_limit,
+            &mut new.rate_limit,
+            &mut pending,
+        );
+        keep("access", &self.access, &mut new.access, &mut pending);
+        keep("reload", &self.reload, &mut new.reload, &mut pending);
+        keep("snapshot", &self.snapshot, &mut new.snapshot, &mut pending);
+        keep(
+            "shutdown.drain_timeout",
+            &self.shutdown.drain_timeout,
+            &mut new.shutdown.drain_timeout,
+            &mut pending,
+        );
+        keep(
+            "bt.reap_interval",
+            &self.bt.reap_interval,
+            &mut new.bt.reap_interval,
+            &mut pending,
+        );
+        keep(
+            "bt.flush_interval",
+            &self.bt.flush_interval,
+            &mut new.bt.flush_interval,
+            &mut pending,
+        );
+        keep(
+            "bans.reload_interval",
+            &self.bans.reload_interval,
+            &mut new.bans.reload_interval,
+            &mut pending,
+        );
+
+        (new, pending)
+    }
+
+    // In strict mode, a configuration that can't be used as written is an
+    // error. Otherwise the defaults are used in its place.
+    pub fn load_config(path: String, strict: bool) -> Result<Config, String> {
+        let config = match Config::read(&path, strict) {
+            Ok(config) => config,
+            Err(e) if strict => return Err(e),
+            Err(e) => {
+                error!("{}", e);
+                error!("Loading default config...");
                 Config::default()
             }
         };
 
         info!("Binding to address: {}", &config.network.binding);
+        if let Some(tls_binding) = &config.network.tls_binding {
+            info!("Binding to TLS address: {}", tls_binding);
+        }
+        if config.network.proxy_protocol {
+            info!("Expecting PROXY protocol headers on all connections");
+        }
+        if !config.network.trusted_proxies.is_empty() {
+            info!(
+                "Trusting forwarding headers from: {:?}",
+                &config.network.trusted_proxies
+            );
+        }
         info!(
-            "Utilizing {} storage backend located at {}",
-            &config.storage.backend, &config.storage.path
+            "Announce 'ip' override policy: {:?}",
+            &config.network.ip_override
         );
+        if config.storage.backend == "memory" {
+            info!("Keeping torrents in memory only");
+        } else {
+            info!(
+                "Utilizing {} storage backend located at {}",
+                &config.storage.backend, &config.storage.path
+            );
+        }
         info!("Announce interval: {} secs", &config.bt.announce_rate);
+        if config.interval.adaptive {
+            info!(
+                "Adapting announce interval between {} and {} secs",
+                &config.interval.min, &config.interval.max
+            );
+        }
         info!(
             "Clearing peers older than {} secs at {}-sec interval",
             &config.bt.peer_timeout, &config.bt.reap_interval
         );
-        info!(
-            "Flushing torrents to disk every {} secs",
-            &config.bt.flush_interval
-        );
+        if config.storage.backend != "memory" {
+            info!(
+                "Flushing torrents to disk every {} secs",
+                &config.bt.flush_interval
+            );
+        }
+        if let Some(path) = &config.snapshot.path {
+            info!(
+                "Saving swarms to {} every {} secs",
+                path, &config.snapshot.interval
+            );
+        }
+        if let Some(file) = &config.bans.file {
+            info!("Loading bans from {}", file);
+        }
+        if config.bans.database {
+            info!("Loading bans from the database");
+        }
+        if config.rate_limit.enabled {
+            info!(
+                "Rate limiting announces to {}/sec, scrapes to {}/sec and stats to {}/sec per client",
+                &config.rate_limit.announce.rate,
+                &config.rate_limit.scrape.rate,
+                &config.rate_limit.stats.rate
+            );
+        }
+        if !config.access.scrape.enabled {
+            info!("Scrapes are disabled");
+        }
+        if !config.access.stats.enabled {
+            info!("Statistics are disabled");
+        }
         info!("Client list: {:?}", &config.client_approval.client_list);
+        if !config.client_approval.rules.is_empty() {
+            info!("Client rules: {:?}", &config.client_approval.rules);
+        }
+        if config.client_approval.enabled && config.client_approval.shadow {
+            info!("Client approval is in shadow mode; rejections are only logged");
+        }
+
+        Ok(config)
+    }
+}
+
+// Goes through every key of every section on its own, so that all unknown
+// keys and invalid values are found rather than only the first. This relies
+// on every section filling in its defaults for whatever else is missing.
+fn check(table: &Table) -> (Vec<String>, Vec<String>) {
+    let mut unknown = Vec::new();
+    let mut invalid = Vec::new();
+
+    for (name, value) in table {
+        let (unknown_keys, invalid_values) = match name.as_str() {
+            "network" => check_section::<Network>(name, value),
+            "storage" => check_section::<Storage>(name, value),
+            "bt" => check_section::<BitTorrent>(name, value),
+            "client_approval" => check_section::<ClientApproval>(name, value),
+            "interval" => check_section::<Interval>(name, value),
+            "bans" => check_section::<Bans>(name, value),
+            "rate_limit" => check_section::<RateLimit>(name, value),
+            "access" => check_section::<Access>(name, value),
+            "log" => check_section::<Log>(name, value),
+            "reload" => check_section::<Reload>(name, value),
+            "snapshot" => check_section::<Snapshot>(name, value),
+            "shutdown" => check_section::<Shutdown>(name, value),
+            _ => (vec![name.clone()], Vec::new()),
+        };
+        unknown.extend(unknown_keys);
+        invalid.extend(invalid_values);
+    }
+
+    (unknown, invalid)
+}
+
+fn check_section<T: DeserializeOwned>(name: &str, value: &Value) -> (Vec<String>, Vec<String>) {
+    let mut unknown = Vec::new();
+    let mut invalid = Vec::new();
+
+    let table = match value {
+        Value::Table(table) => table,
+        _ => return (unknown, vec![format!("[{}] must be a table", name)]),
+    };
+
+    for (key, value) in table {
+        let mut single = Table::new();
+        single.insert(key.clone(), value.clone());
+
+        let result: Result<T, _> = serde_ignored::deserialize(Value::Table(single), |path| {
+            unknown.push(format!("{}.{}", name, path))
+        });
+        if let Err(e) = result {
+            let message = e.to_string().replace('\n', " ");
+            invalid.push(format!("{}.{}: {}", name, key, message.trim()));
+        }
+    }
+
+    (unknown, invalid)
+}
+
+// Variables named like TYTO_SECTION__KEY override 'key' in '[section]'.
+// Further levels are separated by double underscores as well, e.g.
+// TYTO_RATE_LIMIT__ANNOUNCE__BURST. Values are read as TOML where
+// possible, so numbers, booleans and arrays work, and as strings otherwise.
+// A value that only reads as TOML by accident, like a password of 123456,
+// is kept as a string when that's what the setting takes.
+fn apply_overrides<I: Iterator<Item = (String, String)>>(table: &mut Table, vars: I) {
+    for (name, raw) in vars {
+        let path = match name.strip_prefix("TYTO_") {
+            Some(path) if path.contains("__") => path.to_lowercase(),
+            _ => continue,
+        };
+        let keys: Vec<&str> = path.split("__").collect();
+
+        let parsed = format!("value = {}", raw)
+            .parse::<Table>()
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"));
+        let value = match parsed {
+            Some(parsed)
+                if !parsed.is_str()
+                    && !fits(&keys, &parsed)
+                    && fits(&keys, &Value::String(raw.clone())) =>
+            {
+                Value::String(raw)
+            }
+            Some(parsed) => parsed,
+            None => Value::String(raw),
+        };
+
+        let mut current = &mut *table;
+        for key in &keys[..keys.len() - 1] {
+            let entry = current
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            current = entry.as_table_mut().unwrap();
+        }
+        current.insert(keys[keys.len() - 1].to_string(), value);
+    }
+}
+
+// Whether the setting at 'keys' would take the value
+fn fits(keys: &[&str], value: &Value) -> bool {
+    let mut nested = value.clone();
+    for key in keys.iter().rev() {
+        let mut table = Table::new();
+        table.insert(key.to_string(), nested);
+        nested = Value::Table(table);
+    }
+
+    match nested {
+        Value::Table(table) => check(&table).1.is_empty(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_example_parses() {
+        Config::read("config.toml", true).unwrap();
+    }
+
+    #[test]
+    fn config_lists_every_problem() {
+        let table = r#"
+            typo = 1
+
+            [network]
+            binding = 8080
+            bindng = '0.0.0.0:1'
+
+            [bt]
+            peer_timeout = 'soon'
+            reap_interval = -1
+
+            [rate_limit.announce]
+            rate = 1.0
+            burst = 1
+            brust = 2
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        let (mut unknown, invalid) = check(&table);
+        unknown.sort();
+
+        assert_eq!(
+            unknown,
+            vec!["network.bindng", "rate_limit.announce.brust", "typo"]
+        );
+        assert_eq!(invalid.len(), 3);
+        for key in &["network.binding", "bt.peer_timeout", "bt.reap_interval"] {
+            assert!(invalid.iter().any(|problem| problem.starts_with(key)));
+        }
+    }
+
+    #[test]
+    fn config_validation() {
+        let mut config = Config::default();
+        assert!(config.validate().is_empty());
+
+        config.bt.flush_interval = 0;
+        config.bt.default_numwant = 500;
+        config.log.level = "loud".to_string();
+        assert_eq!(config.validate().len(), 3);
+    }
+
+    #[test]
+    fn config_environment_overrides() {
+        let mut table = "[network]\nbinding = '0.0.0.0:1'".parse::<Table>().unwrap();
+        let vars = vec![
+            ("TYTO_NETWORK__BINDING", "127.0.0.1:2"),
+            ("TYTO_BT__PEER_TIMEOUT", "60"),
+            ("TYTO_RATE_LIMIT__ANNOUNCE__BURST", "5"),
+            ("TYTO_STORAGE__PASSWORD", "123456"),
+            ("TYTO_STORAGE__PATH", "true"),
+            ("TYTO_BT__EXTERNAL_IP", "true"),
+            ("TYTO_IGNORED", "1"),
+            ("HOME", "/root"),
+        ];
+        apply_overrides(
+            &mut table,
+            vars.into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        assert_eq!(table["network"]["binding"].as_str(), Some("127.0.0.1:2"));
+        assert_eq!(table["bt"]["peer_timeout"].as_integer(), Some(60));
+        assert_eq!(
+            table["rate_limit"]["announce"]["burst"].as_integer(),
+            Some(5)
+        );
+        assert_eq!(table["storage"]["password"].as_str(), Some("123456"));
+        assert_eq!(table["storage"]["path"].as_str(), Some("true"));
+        assert_eq!(table["bt"]["external_ip"].as_bool(), Some(true));
+        assert_eq!(table.len(), 4);
+
+        let storage = Storage::deserialize(table["storage"].clone()).unwrap();
+        assert_eq!(storage.password.as_deref(), Some("123456"));
+    }
+
+    #[test]
+    fn config_reload_keeps_restart_settings() {
+        let current = Config::default();
+        let mut new = Config::default();
+        new.network.binding = "0.0.0.0:7000".to_string();
+        new.bt.peer_timeout = 60;
+        new.bt.flush_interval = 5;
+        new.client_approval.enabled = true;
+        new.log.level = "debug".to_string();
+
+        let (config, pending) = current.reloaded(new);
 
-        config
+        assert_eq!(pending, vec!["network", "bt.flush_interval"]);
+        assert_eq!(config.network.binding, current.network.binding);
+        assert_eq!(config.bt.flush_interval, current.bt.flush_interval);
+        assert_eq!(config.bt.peer_timeout, 60);
+        assert!(config.client_approval.enabled);
+        assert_eq!(config.log.level_filter(), Ok(LevelFilter::Debug));
     }
 }
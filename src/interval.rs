@@ -0,0 +1,143 @@
+use rand::Rng;
+
+use crate::config::{Config, Interval};
+
+// Works out how long a client should wait before announcing again,
+// along with the minimum interval it must respect, if any.
+pub fn announce_interval(
+    config: &Config,
+    swarm_size: u32,
+    request_rate: f64,
+) -> (u32, Option<u32>) {
+    if !config.interval.adaptive {
+        return (config.bt.announce_rate as u32, None);
+    }
+
+    let bounds = &config.interval;
+    let (min, max) = (bounds.min as f64, bounds.max.max(bounds.min) as f64);
+
+    // All factors go in first, so that neither the load nor the jitter
+    // gets cut off by bounds that were applied halfway through
+    let interval = scaled_interval(bounds, swarm_size, request_rate) * jitter(bounds);
+    let interval = interval.max(min).min(max).round() as u32;
+
+    // Clients shouldn't be allowed to hammer the tracker just because
+    // their swarm is small, so min_interval moves along with the interval
+    (interval, Some(interval / 2))
+}
+
+// Tiny swarms announce at the lower bound so they can find fresh peers quickly,
+// huge ones at the upper bound. Anything in between is placed on a log scale
+// since swarm sizes span several orders of magnitude.
+fn scaled_interval(bounds: &Interval, swarm_size: u32, request_rate: f64) -> f64 {
+    let (min, max) = (bounds.min as f64, bounds.max.max(bounds.min) as f64);
+    let small = f64::from(bounds.small_swarm.max(1));
+    let large = f64::from(bounds.large_swarm).max(small);
+    let size = f64::from(swarm_size);
+
+    let position = if size <= small {
+        0.0
+    } else if size >= large {
+        1.0
+    } else {
+        (size.ln() - small.ln()) / (large.ln() - small.ln())
+    };
+
+    let mut interval = min + (max - min) * position;
+
+    // Under heavy load, everyone backs off proportionally
+    if bounds.load_threshold > 0.0 && request_rate > bounds.load_threshold {
+        interval *= request_rate / bounds.load_threshold;
+    }
+
+    interval
+}
+
+// Spreading announces out randomly keeps peers that
+// started together from announcing together forever
+fn jitter(bounds: &Interval) -> f64 {
+    let jitter = bounds.jitter.abs().min(1.0);
+
+    if jitter > 0.0 {
+        1.0 + rand::thread_rng().gen_range(-jitter, jitter)
+    } else {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adaptive_config() -> Config {
+        let mut config = Config::default();
+        config.interval.adaptive = true;
+        config.interval.jitter = 0.0;
+        config
+    }
+
+    #[test]
+    fn interval_static_when_not_adaptive() {
+        let config = Config::default();
+        assert_eq!(
+            announce_interval(&config, 5000, 0.0),
+            (config.bt.announce_rate as u32, None)
+        );
+    }
+
+    #[test]
+    fn interval_scales_with_swarm_size() {
+        let config = adaptive_config();
+
+        let (tiny, _) = announce_interval(&config, 2, 0.0);
+        let (medium, _) = announce_interval(&config, 100, 0.0);
+        let (huge, _) = announce_interval(&config, 50000, 0.0);
+
+        assert_eq!(tiny as u64, config.interval.min);
+        assert!(tiny < medium && medium < huge);
+        assert_eq!(huge as u64, config.interval.max);
+    }
+
+    #[test]
+    fn interval_raised_under_load() {
+        let config = adaptive_config();
+        let threshold = config.interval.load_threshold;
+
+        let (idle, _) = announce_interval(&config, 2, threshold / 2.0);
+        let (loaded, _) = announce_interval(&config, 2, threshold * 2.0);
+
+        assert_eq!(loaded, idle * 2);
+    }
+
+    #[test]
+    fn interval_clamped_after_load() {
+        let config = adaptive_config();
+        let threshold = config.interval.load_threshold;
+
+        // The load pushes a medium swarm past the upper bound
+        let (medium, _) = announce_interval(&config, 100, 0.0);
+        assert!(medium as u64 > config.interval.max / 2);
+        let (loaded, _) = announce_interval(&config, 100, threshold * 2.0);
+        assert_eq!(loaded as u64, config.interval.max);
+
+        let mut config = adaptive_config();
+        config.interval.jitter = 0.5;
+        for _ in 0..100 {
+            let (interval, _) = announce_interval(&config, 100, threshold * 4.0);
+            assert_eq!(interval as u64, config.interval.max);
+        }
+    }
+
+    #[test]
+    fn interval_jitter_stays_within_bounds() {
+        let mut config = adaptive_config();
+        config.interval.jitter = 0.5;
+
+        for _ in 0..100 {
+            let (interval, min_interval) = announce_interval(&config, 100, 0.0);
+            assert!(interval as u64 >= config.interval.min);
+            assert!(interval as u64 <= config.interval.max);
+            assert_eq!(min_interval, Some(interval / 2));
+        }
+    }
+}
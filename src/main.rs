@@ -1,31 +1,54 @@
 pub mod bencode;
 pub mod bittorrent;
 pub mod config;
+pub mod interval;
 pub mod network;
+pub mod obfuscation;
+pub mod peer_id;
+pub mod reload;
+pub mod shutdown;
 pub mod state;
 pub mod statistics;
 pub mod storage;
 pub mod util;
 
+use std::sync::Arc;
+
 use actix::prelude::*;
+#[allow(clippy::single_component_path_imports)]
 use actix_rt;
 use actix_web::{middleware, web, App, HttpResponse, HttpServer};
-use clap::{App as ClapApp, Arg};
+use clap::{App as ClapApp, Arg, SubCommand};
 use config::Config;
-use mysql;
+use network::middleware::Denial;
+use network::rate_limit::RateLimiter;
+use network::tls::CertificateResolver;
+#[allow(clippy::single_component_path_imports)]
 use pretty_env_logger;
+use reload::Reloader;
 use state::State;
+use statistics::StorageStatus;
 use storage::janitor::Janitor;
+use storage::snapshot::Snapshot;
 
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    // Everything passes the logger itself unless RUST_LOG says otherwise,
+    // so that the level from the configuration can be changed at runtime
+    let mut logger = pretty_env_logger::formatted_timed_builder();
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => logger.parse_filters(&filters),
+        Err(_) => logger.filter_level(log::LevelFilter::Trace),
+    };
+    logger.init();
     if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
+        log::set_max_level(log::LevelFilter::Info);
     }
-    pretty_env_logger::init_timed();
 
     let matches = ClapApp::new("tyto")
         .version("0.2.1")
@@ -37,57 +60,283 @@ async fn main() -> std::io::Result<()> {
                 .long("configuration")
                 .value_name("CONFIG_FILE")
                 .help("Start the tracker using this configuration")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Refuse to start if the configuration has unknown keys or invalid values"),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Bring the database schema up to date with this version and exit"),
         )
         .get_matches();
 
     // Parse arguments and attempt to parse configuration file
-    let config_path = matches.value_of("config");
-    let config = match config_path {
-        Some(path) => Config::load_config(path.to_string()),
-        None => Config::load_config("config.toml".to_string()),
+    let config_path = matches
+        .value_of("config")
+        .unwrap_or("config.toml")
+        .to_string();
+    let strict = matches.is_present("strict");
+    let config = match Config::load_config(config_path.clone(), strict) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
     };
+    if std::env::var("RUST_LOG").is_err() {
+        log::set_max_level(config.log.level_filter().unwrap_or(log::LevelFilter::Info));
+    }
+
+    // The schema is only ever changed on request, never by starting the tracker
+    if matches.subcommand_matches("migrate").is_some() {
+        if let Err(e) = storage::migrations::run(&config.storage) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     // Copy and cloning up here to avoid errors for moved values
     let binding = config.network.binding.clone();
 
-    // TODO: abstract into a general loading function
-    // TODO: add support to pass mysql password
+    // The snapshot from the last run restores swarms, and also torrents
+    // if the tracker has to start without its database
+    let mut snapshot = match &config.snapshot.path {
+        Some(path) if std::path::Path::new(path).exists() => match Snapshot::read(path) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                warn!("Could not restore peer snapshot: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
     // Collect torrents from desired storage
     // backend and instantiate data stores.
-    let pool = mysql::Pool::new(&config.storage.path).unwrap();
-    let torrents = storage::mysql::get_torrents(pool.clone()).unwrap();
-    let torrent_records = storage::TorrentStore::new(torrents.clone());
+    let (backend, torrents, dirty, degraded) =
+        match storage::backend::connect(&config.storage).await {
+            Ok((backend, torrents)) => (backend, torrents, Vec::new(), false),
+            Err(e) => match snapshot.as_mut() {
+                Some(snapshot) if config.storage.degraded_start => {
+                    error!("Could not load torrents: {}", e);
+                    warn!("Starting from the snapshot until storage is available again");
+                    let deferred = storage::backend::Deferred::new(&config.storage)
+                        .map_err(std::io::Error::other)?;
+                    let (torrents, dirty) = snapshot.take_torrents();
+                    let backend: Arc<dyn storage::backend::TorrentBackend> = Arc::new(deferred);
+                    (backend, torrents, dirty, true)
+                }
+                _ => {
+                    error!("Could not load torrents: {}", e);
+                    std::process::exit(1);
+                }
+            },
+        };
+    info!("Using {} storage backend", backend.name());
+    if !backend.persistent() {
+        warn!("Torrent statistics will not be kept across restarts");
+    }
+    let num_torrents = torrents.len();
+    let mut torrent_records = storage::TorrentStore::new(torrents);
+    if !backend.persistent() {
+        torrent_records = torrent_records.register_unknown();
+    }
+    torrent_records.mark_dirty(&dirty);
     let state = web::Data::new(State::new(config.clone(), torrent_records));
+    let mut storage_status = StorageStatus::new(backend.name(), degraded);
+    if !degraded {
+        storage_status.succeeded(network::ban::now());
+    }
+    *state.storage.write() = storage_status;
     let janitor_state_clone = state.clone();
-    info!("Number of torrents loaded: {}", torrents.len());
+    let reload_state = state.clone();
+    let shutdown_state = state.clone();
+    info!("Number of torrents loaded: {}", num_torrents);
+
+    // Swarms saved by the last run are restored, minus peers that have timed out
+    if let Some(snapshot) = snapshot {
+        let peer_timeout = std::time::Duration::new(config.bt.peer_timeout, 0);
+        let (seeders, leechers) = snapshot.restore(&state.peer_store, peer_timeout).await;
+        state.stats.write().await.restored_peers(seeders, leechers);
+        info!(
+            "Restored {} seeders and {} leechers from {}",
+            seeders,
+            leechers,
+            config.snapshot.path.as_deref().unwrap_or_default()
+        );
+    }
 
-    let server = HttpServer::new(move || {
+    // Torrents added while the database was unavailable are picked up
+    // by reconciling everything once it's back
+    let reconcile_since = if degraded { 0 } else { network::ban::now() };
+
+    if config.bans.enabled() {
+        let bans = storage::janitor::collect_bans(&config, backend.as_ref());
+        info!("Number of bans loaded: {}", state.bans.replace(bans));
+    }
+
+    // Certificates are loaded up front so that a bad path or key
+    // fails at startup instead of on the first handshake
+    let certificates = match &config.network.tls_binding {
+        Some(_) => match (&config.network.tls_cert, &config.network.tls_key) {
+            (Some(cert), Some(key)) => Some(Arc::new(CertificateResolver::new(
+                cert.clone(),
+                key.clone(),
+            )?)),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "tls_binding requires both tls_cert and tls_key",
+                ))
+            }
+        },
+        None => None,
+    };
+    let tls_binding = config.network.tls_binding.clone();
+    let proxy_protocol = config.network.proxy_protocol;
+    let proxy_protocol_sources = config.network.trusted_proxies.clone();
+    let drain_timeout = config.shutdown.drain_timeout;
+    let janitor_certificates = certificates.clone();
+
+    let client_approval = network::middleware::ClientApproval::new(&config.client_approval)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let reload_client_approval = client_approval.clone();
+
+    // Limiters are shared between all workers
+    let rate_limit = &config.rate_limit;
+    let announce_limiter = Arc::new(RateLimiter::new(
+        rate_limit.announce,
+        rate_limit.max_clients,
+    ));
+    let scrape_limiter = Arc::new(RateLimiter::new(rate_limit.scrape, rate_limit.max_clients));
+    let stats_limiter = Arc::new(RateLimiter::new(rate_limit.stats, rate_limit.max_clients));
+
+    let app = move || {
         App::new()
             .app_data(state.clone())
             // Log all requests to stdout
             //.wrap(middleware::Logger::default())
-            // If enabled, filter requests
-            // by client ID and reject or accept
-            .wrap(middleware::Condition::new(
-                config.client_approval.enabled,
-                network::middleware::ClientApproval::new(
-                    config.client_approval.blacklist_style,
-                    config.client_approval.versioned,
-                    config.client_approval.client_list.clone(),
-                ),
-            ))
-            .service(web::scope("announce").route("", web::get().to(network::parse_announce)))
-            .service(web::scope("scrape").route("", web::get().to(network::parse_scrape)))
-            .service(web::scope("stats").route("", web::get().to(network::get_stats)))
-            .service(web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())))
-    })
-    .bind(binding)?
-    .run();
+            // Each route declares its own middleware. Routes have their own
+            // rate limiters, which only see requests from addresses that
+            // aren't banned, and only announces carry a peer ID to approve.
+            .service(
+                web::scope("announce")
+                    // If enabled, filter requests
+                    // by client ID and reject or accept
+                    .wrap(client_approval.clone())
+                    .wrap(middleware::Condition::new(
+                        config.rate_limit.enabled,
+                        network::middleware::RateLimit::new(
+                            announce_limiter.clone(),
+                            config.network.trusted_proxies.clone(),
+                        ),
+                    ))
+                    .wrap(network::middleware::IpBan::new(
+                        state.bans.clone(),
+                        config.network.trusted_proxies.clone(),
+                    ))
+                    .route("", web::get().to(network::parse_announce)),
+            )
+            .service(
+                web::scope("scrape")
+                    .wrap(network::middleware::AccessControl::new(
+                        config.access.scrape.clone(),
+                        config.network.trusted_proxies.clone(),
+                        Denial::Bencoded,
+                    ))
+                    .wrap(middleware::Condition::new(
+                        config.rate_limit.enabled,
+                        network::middleware::RateLimit::new(
+                            scrape_limiter.clone(),
+                            config.network.trusted_proxies.clone(),
+                        ),
+                    ))
+                    .wrap(network::middleware::IpBan::new(
+                        state.bans.clone(),
+                        config.network.trusted_proxies.clone(),
+                    ))
+                    .route("", web::get().to(network::parse_scrape)),
+            )
+            .service(
+                web::scope("stats")
+                    .wrap(network::middleware::AccessControl::new(
+                        config.access.stats.clone(),
+                        config.network.trusted_proxies.clone(),
+                        Denial::Forbidden,
+                    ))
+                    .wrap(middleware::Condition::new(
+                        config.rate_limit.enabled,
+                        network::middleware::RateLimit::new(
+                            stats_limiter.clone(),
+                            config.network.trusted_proxies.clone(),
+                        ),
+                    ))
+                    .route("", web::get().to(network::get_stats)),
+            )
+            .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed)))
+    };
+
+    // Signals are handled below, so that persisting can finish before exiting
+    let server = if proxy_protocol {
+        let tls = match (&tls_binding, &certificates) {
+            (Some(tls_binding), Some(certificates)) => {
+                Some((tls_binding.as_str(), Arc::new(certificates.server_config())))
+            }
+            _ => None,
+        };
+        network::proxy_protocol::server(app, &binding, tls, proxy_protocol_sources, drain_timeout)?
+    } else {
+        let mut server = HttpServer::new(app)
+            .disable_signals()
+            .shutdown_timeout(drain_timeout)
+            .bind(binding)?;
+        if let (Some(tls_binding), Some(certificates)) = (tls_binding, &certificates) {
+            server = server.bind_rustls(tls_binding, certificates.server_config())?;
+        }
+        server.run()
+    };
 
     // Start janitor in its own thread
-    Janitor::create(|_ctx: &mut Context<Janitor>| Janitor::new(janitor_state_clone, pool));
+    let reloader = Reloader::new(
+        config_path,
+        strict,
+        reload_state,
+        reload_client_approval,
+        backend.clone(),
+    );
+    let shutdown_backend = backend.clone();
+    let janitor = Janitor::create(|_ctx: &mut Context<Janitor>| {
+        Janitor::new(
+            janitor_state_clone,
+            backend,
+            janitor_certificates,
+            reconcile_since,
+        )
+    });
+
+    // Reload the configuration on SIGHUP or when the file changes
+    actix_rt::spawn(reloader.run());
+
+    // Start server and wait for a signal to stop it
+    tokio::select! {
+        result = server.clone() => result?,
+        graceful = shutdown::signalled() => {
+            server.stop(graceful?).await;
+            info!("Server stopped, saving state...");
+        }
+    }
+
+    // The janitor may already be gone if it stopped on its own
+    let _ = janitor.send(storage::janitor::Stop).await;
+    if !shutdown::persist(&shutdown_state, shutdown_backend).await {
+        error!("Not everything could be saved before exiting");
+        std::process::exit(1);
+    }
 
-    // Start server
-    server.await
+    Ok(())
 }
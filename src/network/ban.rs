@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::RwLock;
+
+use crate::network::cidr::{canonical, Cidr};
+
+// A banned range of addresses, inclusive on both ends. Bans may
+// carry an expiry time in seconds since the epoch, after which
+// they are ignored and eventually dropped on the next reload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ban {
+    v6: bool,
+    start: u128,
+    end: u128,
+    expires: Option<u64>,
+}
+
+impl Ban {
+    pub fn new(start: IpAddr, end: IpAddr, expires: Option<u64>) -> Option<Ban> {
+        let (v6, start, end) = match (canonical(start), canonical(end)) {
+            (IpAddr::V4(s), IpAddr::V4(e)) => {
+                (false, u128::from(u32::from(s)), u128::from(u32::from(e)))
+            }
+            (IpAddr::V6(s), IpAddr::V6(e)) => (true, u128::from(s), u128::from(e)),
+            _ => return None,
+        };
+        if start > end {
+            return None;
+        }
+
+        Some(Ban {
+            v6,
+            start,
+            end,
+            expires,
+        })
+    }
+
+    pub fn from_cidr(cidr: Cidr, expires: Option<u64>) -> Ban {
+        let (v6, start, host_bits) = match cidr.network() {
+            IpAddr::V4(v4) => (
+                false,
+                u128::from(u32::from(v4)),
+                32 - u32::from(cidr.prefix()),
+            ),
+            IpAddr::V6(v6) => (true, u128::from(v6), 128 - u32::from(cidr.prefix())),
+        };
+        let host_mask = 1u128.checked_shl(host_bits).map_or(u128::MAX, |m| m - 1);
+
+        Ban {
+            v6,
+            start,
+            end: start | host_mask,
+            expires,
+        }
+    }
+
+    pub fn with_expiry(self, expires: Option<u64>) -> Ban {
+        Ban { expires, ..self }
+    }
+
+    fn is_active(&self, now: u64) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Ranges are kept sorted by their start along with the furthest end
+// seen so far, so a lookup only has to walk back from the last range
+// starting at or before the address until nothing further can reach it.
+#[derive(Default)]
+struct Ranges {
+    bans: Vec<Ban>,
+    reach: Vec<u128>,
+}
+
+impl Ranges {
+    fn new(mut bans: Vec<Ban>) -> Ranges {
+        bans.sort_by_key(|ban| ban.start);
+
+        let mut furthest = 0;
+        let reach = bans
+            .iter()
+            .map(|ban| {
+                furthest = furthest.max(ban.end);
+                furthest
+            })
+            .collect();
+
+        Ranges { bans, reach }
+    }
+
+    fn contains(&self, addr: u128, now: u64) -> bool {
+        let upper = self.bans.partition_point(|ban| ban.start <= addr);
+
+        for i in (0..upper).rev() {
+            if self.reach[i] < addr {
+                return false;
+            }
+            let ban = &self.bans[i];
+            if ban.end >= addr && ban.is_active(now) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct BanList {
+    v4: RwLock<Ranges>,
+    v6: RwLock<Ranges>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    pub fn is_banned(&self, addr: IpAddr) -> bool {
+        match canonical(addr) {
+            IpAddr::V4(v4) => self.v4.read().contains(u128::from(u32::from(v4)), now()),
+            IpAddr::V6(v6) => self.v6.read().contains(u128::from(v6), now()),
+        }
+    }
+
+    // Swaps in a freshly loaded set of bans, leaving out any that have expired
+    pub fn replace(&self, bans: Vec<Ban>) -> usize {
+        let now = now();
+        let (v6, v4): (Vec<Ban>, Vec<Ban>) = bans
+            .into_iter()
+            .filter(|ban| ban.is_active(now))
+            .partition(|ban| ban.v6);
+        let total = v4.len() + v6.len();
+
+        *self.v4.write() = Ranges::new(v4);
+        *self.v6.write() = Ranges::new(v6);
+
+        total
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.read().bans.len() + self.v6.read().bans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Ban files hold one entry per line. An entry is either a CIDR network,
+// a single address or a 'start-end' range, optionally followed by an
+// expiry time in seconds since the epoch. Lines in the P2P blocklist
+// format ('description:start-end') are accepted as well.
+pub fn parse_entry(line: &str) -> Option<Ban> {
+    let line = line.trim();
+
+    if let Some((range, expires)) = line.rsplit_once(char::is_whitespace) {
+        if let (Some(ban), Ok(expires)) = (parse_range(range.trim()), expires.parse::<u64>()) {
+            return Some(ban.with_expiry(Some(expires)));
+        }
+    }
+
+    parse_range(line).or_else(|| {
+        let (_, range) = line.rsplit_once(':')?;
+        parse_range(range.trim())
+    })
+}
+
+fn parse_range(range: &str) -> Option<Ban> {
+    if let Some((start, end)) = range.split_once('-') {
+        let start = start.trim().parse::<IpAddr>().ok()?;
+        let end = end.trim().parse::<IpAddr>().ok()?;
+        return Ban::new(start, end, None);
+    }
+
+    range
+        .parse::<Cidr>()
+        .ok()
+        .map(|cidr| Ban::from_cidr(cidr, None))
+}
+
+pub fn load_file(path: &str) -> io::Result<Vec<Ban>> {
+    let mut bans = Vec::new();
+
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let entry = line.split('#').next().unwrap_or("").trim();
+        if entry.is_empty() {
+            continue;
+        }
+
+        match parse_entry(entry) {
+            Some(ban) => bans.push(ban),
+            None => warn!("Skipping invalid ban on line {} of {}", number + 1, path),
+        }
+    }
+
+    Ok(bans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_list_formats() {
+        let bans = vec![
+            parse_entry("10.0.0.0/8").unwrap(),
+            parse_entry("Some Organization:192.0.2.0-192.0.2.255").unwrap(),
+            parse_entry("2001:db8::/32").unwrap(),
+            parse_entry("198.51.100.7").unwrap(),
+        ];
+        assert!(parse_entry("not a network").is_none());
+        assert!(parse_entry("192.0.2.9-192.0.2.1").is_none());
+
+        let list = BanList::new();
+        assert_eq!(list.replace(bans), 4);
+
+        assert!(list.is_banned("10.20.30.40".parse().unwrap()));
+        assert!(list.is_banned("192.0.2.128".parse().unwrap()));
+        assert!(list.is_banned("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(list.is_banned("2001:db8:1::1".parse().unwrap()));
+        assert!(list.is_banned("198.51.100.7".parse().unwrap()));
+
+        assert!(!list.is_banned("198.51.100.8".parse().unwrap()));
+        assert!(!list.is_banned("11.0.0.1".parse().unwrap()));
+        assert!(!list.is_banned("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ban_list_overlapping_ranges() {
+        let list = BanList::new();
+        list.replace(vec![
+            parse_entry("10.0.0.0/8").unwrap(),
+            parse_entry("10.1.0.0/16").unwrap(),
+            parse_entry("10.200.0.0-10.200.0.10").unwrap(),
+        ]);
+
+        // The small range starting later must not hide the large one
+        assert!(list.is_banned("10.250.0.1".parse().unwrap()));
+        assert!(list.is_banned("10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn ban_list_expiry() {
+        let expired = parse_entry(&format!("203.0.113.0/24 {}", now() - 1)).unwrap();
+        let active = parse_entry(&format!("198.51.100.0/24 {}", now() + 3600)).unwrap();
+        assert_eq!(active.expires, Some(now() + 3600));
+
+        let list = BanList::new();
+        assert_eq!(list.replace(vec![expired, active]), 1);
+
+        assert!(!list.is_banned("203.0.113.1".parse().unwrap()));
+        assert!(list.is_banned("198.51.100.1".parse().unwrap()));
+    }
+}
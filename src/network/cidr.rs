@@ -0,0 +1,155 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+// A network in CIDR notation, e.g. '10.0.0.0/8' or '2001:db8::/32'.
+// A bare address is treated as a network containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, String> {
+        let max = max_prefix(addr);
+        if prefix > max {
+            return Err(format!("Prefix /{} is too long for {}", prefix, addr));
+        }
+
+        Ok(Cidr {
+            network: mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        match (self.network, addr) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask(addr, self.prefix) == self.network
+            }
+            _ => false,
+        }
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+}
+
+// IPv4 clients connecting to a dual-stack socket show up as
+// IPv4-mapped IPv6 addresses, which should match IPv4 networks
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+// Zeroes out all of the host bits of an address
+pub fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4((bits & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6((bits & mask).into())
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = parts
+            .next()
+            .unwrap_or("")
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid network address: {}", s))?;
+        let addr = canonical(addr);
+
+        let prefix = match parts.next() {
+            Some(p) => p
+                .parse::<u8>()
+                .map_err(|_| format!("Invalid network prefix: {}", s))?,
+            None => max_prefix(addr),
+        };
+
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> Result<Cidr, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_parse_and_contains_v4() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert!(cidr.contains("10.255.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_parse_and_contains_v6() {
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db9::1".parse().unwrap()));
+        assert!(!cidr.contains("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_bare_address_and_errors() {
+        let cidr: Cidr = "192.168.1.1".parse().unwrap();
+        assert_eq!(cidr.prefix(), 32);
+        assert!(cidr.contains("192.168.1.1".parse().unwrap()));
+        assert!(!cidr.contains("192.168.1.2".parse().unwrap()));
+
+        assert!("192.168.1.1/33".parse::<Cidr>().is_err());
+        assert!("not-an-ip/8".parse::<Cidr>().is_err());
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.7".parse().unwrap()));
+    }
+}
@@ -0,0 +1,137 @@
+use std::net::IpAddr;
+
+use crate::config::{IpOverride, Network};
+use crate::network::cidr::{canonical, Cidr};
+use crate::network::proxy::is_trusted;
+
+// Loopback, RFC 1918, unique local and link-local blocks. Two addresses
+// are on the same network if they both fall into the same one of these.
+lazy_static! {
+    static ref PRIVATE_NETWORKS: Vec<Cidr> = [
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "127.0.0.0/8",
+        "fc00::/7",
+        "fe80::/10",
+        "::1/128",
+    ]
+    .iter()
+    .map(|cidr| cidr.parse().unwrap())
+    .collect();
+}
+
+// Decides whether a client connecting from 'remote' may
+// register itself under the 'requested' address instead
+pub fn permits_override(network: &Network, remote: Option<IpAddr>, requested: IpAddr) -> bool {
+    let requested = canonical(requested);
+    let remote = match remote {
+        Some(remote) => canonical(remote),
+        None => return network.ip_override == IpOverride::Allow,
+    };
+
+    if requested == remote {
+        return true;
+    }
+
+    match network.ip_override {
+        IpOverride::Allow => true,
+        IpOverride::Ignore => false,
+        IpOverride::Trusted => {
+            is_trusted(remote, &network.trusted_proxies)
+                || is_trusted(remote, &network.ip_override_networks)
+        }
+        IpOverride::SameNetwork => match private_network(requested) {
+            Some(cidr) => cidr.contains(remote),
+            None => false,
+        },
+    }
+}
+
+fn private_network(addr: IpAddr) -> Option<Cidr> {
+    PRIVATE_NETWORKS
+        .iter()
+        .find(|cidr| cidr.contains(addr))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ip_override: IpOverride) -> Network {
+        Network {
+            ip_override,
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap()],
+            ip_override_networks: vec!["198.51.100.0/24".parse().unwrap()],
+            ..Network::default()
+        }
+    }
+
+    #[test]
+    fn ip_override_allow_and_ignore() {
+        let remote = Some("203.0.113.5".parse().unwrap());
+        let requested = "192.0.2.1".parse().unwrap();
+
+        assert!(permits_override(
+            &network(IpOverride::Allow),
+            remote,
+            requested
+        ));
+        assert!(!permits_override(
+            &network(IpOverride::Ignore),
+            remote,
+            requested
+        ));
+
+        // Asking for the address we already see is never an override
+        assert!(permits_override(
+            &network(IpOverride::Ignore),
+            remote,
+            "203.0.113.5".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn ip_override_trusted() {
+        let requested = "192.0.2.1".parse().unwrap();
+        let policy = network(IpOverride::Trusted);
+
+        assert!(permits_override(
+            &policy,
+            Some("10.0.0.1".parse().unwrap()),
+            requested
+        ));
+        assert!(permits_override(
+            &policy,
+            Some("198.51.100.7".parse().unwrap()),
+            requested
+        ));
+        assert!(!permits_override(
+            &policy,
+            Some("203.0.113.5".parse().unwrap()),
+            requested
+        ));
+    }
+
+    #[test]
+    fn ip_override_same_network() {
+        let policy = network(IpOverride::SameNetwork);
+
+        assert!(permits_override(
+            &policy,
+            Some("192.168.1.1".parse().unwrap()),
+            "192.168.1.20".parse().unwrap()
+        ));
+        assert!(!permits_override(
+            &policy,
+            Some("10.0.0.5".parse().unwrap()),
+            "192.168.1.20".parse().unwrap()
+        ));
+        assert!(!permits_override(
+            &policy,
+            Some("192.168.1.1".parse().unwrap()),
+            "192.0.2.1".parse().unwrap()
+        ));
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, Either, Ready};
+
+use crate::bencode;
+use crate::bittorrent::{self, ScrapeResponse};
+use crate::config::RouteAccess;
+use crate::network::cidr::Cidr;
+use crate::network::proxy;
+
+// How a refusal is presented to the client: BitTorrent clients expect a
+// bencoded failure, while anything else is better served by a plain 403
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Denial {
+    Bencoded,
+    Forbidden,
+}
+
+#[derive(Clone)]
+pub struct AccessControl {
+    rule: Arc<RouteAccess>,
+    trusted_proxies: Arc<Vec<Cidr>>,
+    denial: Denial,
+}
+
+impl AccessControl {
+    pub fn new(rule: RouteAccess, trusted_proxies: Vec<Cidr>, denial: Denial) -> Self {
+        AccessControl {
+            rule: Arc::new(rule),
+            trusted_proxies: Arc::new(trusted_proxies),
+            denial,
+        }
+    }
+
+    fn permits(&self, req: &ServiceRequest) -> bool {
+        if !self.rule.enabled {
+            return false;
+        }
+
+        if !self.rule.allow.is_empty() {
+            match proxy::service_client_ip(req, &self.trusted_proxies) {
+                Some(ip) if proxy::is_trusted(ip, &self.rule.allow) => {}
+                _ => return false,
+            }
+        }
+
+        match &self.rule.token {
+            Some(token) => presented_token(req).as_deref() == Some(token.as_bytes()),
+            None => true,
+        }
+    }
+}
+
+fn presented_token(req: &ServiceRequest) -> Option<Vec<u8>> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().as_bytes().to_vec());
+
+    bearer.or_else(|| bittorrent::raw_param(req.query_string(), "token"))
+}
+
+impl<S, B> Transform<S> for AccessControl
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessControlMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessControlMiddleware {
+            service,
+            access: self.clone(),
+        })
+    }
+}
+
+pub struct AccessControlMiddleware<S> {
+    service: S,
+    access: AccessControl,
+}
+
+impl<S, B> Service for AccessControlMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.access.permits(&req) {
+            return Either::Left(self.service.call(req));
+        }
+
+        let response = match self.access.denial {
+            Denial::Bencoded => {
+                let failure = ScrapeResponse::failure("Access denied".to_string());
+                HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .body(bencode::encode_scrape_response(failure))
+            }
+            Denial::Forbidden => HttpResponse::Forbidden().finish(),
+        };
+
+        Either::Right(ok(req.into_response(response.into_body())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, web, App};
+
+    async fn status(access: AccessControl, req: test::TestRequest) -> u16 {
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("stats")
+                    .wrap(access)
+                    .route("", web::get().to(|| HttpResponse::Ok().finish())),
+            ),
+        )
+        .await;
+
+        test::call_service(&mut app, req.to_request())
+            .await
+            .status()
+            .as_u16()
+    }
+
+    #[actix_rt::test]
+    async fn access_by_network_and_token() {
+        let access = AccessControl::new(
+            RouteAccess {
+                enabled: true,
+                allow: vec!["127.0.0.1/32".parse().unwrap()],
+                token: Some("secret".to_string()),
+            },
+            Vec::new(),
+            Denial::Forbidden,
+        );
+        let local = "127.0.0.1:40000".parse().unwrap();
+
+        let req = test::TestRequest::with_uri("/stats?token=secret").peer_addr(local);
+        assert_eq!(status(access.clone(), req).await, 200);
+
+        let req = test::TestRequest::with_uri("/stats")
+            .peer_addr(local)
+            .header("Authorization", "Bearer secret");
+        assert_eq!(status(access.clone(), req).await, 200);
+
+        let req = test::TestRequest::with_uri("/stats?token=wrong").peer_addr(local);
+        assert_eq!(status(access.clone(), req).await, 403);
+
+        let req = test::TestRequest::with_uri("/stats?token=secret")
+            .peer_addr("203.0.113.1:40000".parse().unwrap());
+        assert_eq!(status(access, req).await, 403);
+    }
+
+    #[actix_rt::test]
+    async fn access_disabled_route() {
+        let access = AccessControl::new(
+            RouteAccess {
+                enabled: false,
+                ..RouteAccess::default()
+            },
+            Vec::new(),
+            Denial::Forbidden,
+        );
+
+        let req =
+            test::TestRequest::with_uri("/stats").peer_addr("127.0.0.1:40000".parse().unwrap());
+        assert_eq!(status(access, req).await, 403);
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, Either, Ready};
+
+use crate::bencode;
+use crate::bittorrent::AnnounceResponse;
+use crate::network::ban::BanList;
+use crate::network::cidr::Cidr;
+use crate::network::proxy;
+
+pub struct IpBan {
+    bans: Arc<BanList>,
+    trusted_proxies: Arc<Vec<Cidr>>,
+}
+
+impl IpBan {
+    pub fn new(bans: Arc<BanList>, trusted_proxies: Vec<Cidr>) -> Self {
+        IpBan {
+            bans,
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for IpBan
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IpBanMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpBanMiddleware {
+            service,
+            bans: self.bans.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+        })
+    }
+}
+
+pub struct IpBanMiddleware<S> {
+    service: S,
+    bans: Arc<BanList>,
+    trusted_proxies: Arc<Vec<Cidr>>,
+}
+
+impl<S, B> Service for IpBanMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match proxy::service_client_ip(&req, &self.trusted_proxies) {
+            Some(ip) if self.bans.is_banned(ip) => {
+                debug!("Rejecting request from banned address {}", ip);
+
+                let failure = AnnounceResponse::failure("Banned".to_string());
+                let bencoded = bencode::encode_announce_response(failure);
+                Either::Right(ok(req.into_response(
+                    HttpResponse::Ok()
+                        .content_type("text/plain")
+                        .body(bencoded)
+                        .into_body(),
+                )))
+            }
+            _ => Either::Left(self.service.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, web, App};
+
+    use crate::network::ban;
+
+    #[actix_rt::test]
+    async fn banned_address_rejected() {
+        let bans = Arc::new(BanList::new());
+        bans.replace(vec![ban::parse_entry("203.0.113.0/24").unwrap()]);
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .wrap(IpBan::new(bans, Vec::new()))
+                    .route("", web::get().to(|| HttpResponse::Ok().body("passed"))),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/announce")
+            .peer_addr("203.0.113.9:40000".parse().unwrap())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(&body[..], b"d14:failure_reason6:Bannede");
+
+        let req = test::TestRequest::with_uri("/announce")
+            .peer_addr("198.51.100.9:40000".parse().unwrap())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(&body[..], b"passed");
+    }
+}
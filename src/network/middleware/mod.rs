@@ -1,27 +1,107 @@
+mod access;
+mod ban;
+mod rate_limit;
+mod rules;
+
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use actix_service::{Service, Transform};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
 use actix_web::{Error, HttpResponse};
 use futures::future::{ok, Either, Ready};
-use hashbrown::HashSet;
-use url::form_urlencoded;
+use parking_lot::RwLock;
 
 use crate::bencode;
-use crate::bittorrent::AnnounceResponse;
+use crate::bittorrent::{self, AnnounceResponse};
+use crate::config;
+use crate::peer_id;
+
+pub use self::access::{AccessControl, Denial};
+pub use self::ban::IpBan;
+pub use self::rate_limit::RateLimit;
+use self::rules::{Fit, Rule};
 
+const DEFAULT_MESSAGE: &str = "Unsupported Client";
+
+// The compiled rules can be swapped out by a configuration
+// reload while the middleware is in place on every worker
+#[derive(Clone)]
 pub struct ClientApproval {
+    current: Arc<RwLock<Arc<Approval>>>,
+}
+
+struct Approval {
+    enabled: bool,
     blacklist_style: bool,
-    versioned: bool,
-    list: HashSet<String>,
+    shadow: bool,
+    message: String,
+    rules: Vec<Rule>,
 }
 
 impl ClientApproval {
-    pub fn new(blacklist_style: bool, versioned: bool, client_list: Vec<String>) -> Self {
-        ClientApproval {
-            blacklist_style,
-            versioned,
-            list: client_list.into_iter().collect(),
+    // Rules are checked up front so that a bad
+    // pattern or version fails at startup
+    pub fn new(config: &config::ClientApproval) -> Result<Self, String> {
+        Ok(ClientApproval {
+            current: Arc::new(RwLock::new(Arc::new(Approval::new(config)?))),
+        })
+    }
+
+    // On failure, the current rules stay in place
+    pub fn reload(&self, config: &config::ClientApproval) -> Result<(), String> {
+        *self.current.write() = Arc::new(Approval::new(config)?);
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<Approval> {
+        self.current.read().clone()
+    }
+}
+
+impl Approval {
+    fn new(config: &config::ClientApproval) -> Result<Self, String> {
+        Ok(Approval {
+            enabled: config.enabled,
+            blacklist_style: config.blacklist_style,
+            shadow: config.shadow,
+            message: config
+                .message
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MESSAGE.to_string()),
+            rules: rules::compile(config)?,
+        })
+    }
+
+    // Returns the failure message for peer IDs that should be rejected
+    fn check(&self, peer_id: &[u8]) -> Option<String> {
+        // If a client's peer string is empty, this is a Bad Thing
+        if peer_id.is_empty() {
+            return Some(self.message.clone());
+        }
+
+        let decoded = peer_id::decode(peer_id);
+        let mut hint = None;
+
+        for rule in self.rules.iter() {
+            match rule.fit(peer_id, decoded.as_ref()) {
+                Fit::Match => {
+                    // Blacklisted clients are rejected, whitelisted ones let through
+                    return if self.blacklist_style {
+                        Some(rule.message.clone().unwrap_or_else(|| self.message.clone()))
+                    } else {
+                        None
+                    };
+                }
+                Fit::WrongVersion if hint.is_none() => hint = rule.message.clone(),
+                _ => {}
+            }
+        }
+
+        if self.blacklist_style {
+            None
+        } else {
+            Some(hint.unwrap_or_else(|| self.message.clone()))
         }
     }
 }
@@ -41,17 +121,13 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(ClientApprovalMiddleware {
             service,
-            blacklist_style: self.blacklist_style,
-            versioned: self.versioned,
-            list: self.list.clone(),
+            approval: self.clone(),
         })
     }
 }
 pub struct ClientApprovalMiddleware<S> {
     service: S,
-    blacklist_style: bool,
-    versioned: bool,
-    list: HashSet<String>,
+    approval: ClientApproval,
 }
 
 impl<S, B> Service for ClientApprovalMiddleware<S>
@@ -69,67 +145,119 @@ where
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let request_kv_pairs = form_urlencoded::parse(req.query_string().as_bytes()).into_owned();
-        let mut peer_string: String = "".to_string();
-
-        for (k, value) in request_kv_pairs {
-            if let "peer_id" = k.as_str() {
-                peer_string = value
-            }
+        let approval = self.approval.current();
+        if !approval.enabled {
+            return Either::Left(self.service.call(req));
         }
 
-        // If a client's peer string is empty, this is a Bad Thing
-        if peer_string.is_empty() {
-            let failure = AnnounceResponse::failure("Unsupported Client".to_string());
-            let bencoded = bencode::encode_announce_response(failure);
-            Either::Right(ok(req.into_response(
-                HttpResponse::Ok()
-                    .content_type("text/plain")
-                    .body(bencoded)
-                    .into_body(),
-            )))
-        } else {
-            // Most clients do Azureus-style encoding which
-            // looks like '-AZ1234-' followed by a random string
-            let client_check = if self.versioned {
-                &peer_string[1..7]
-            } else {
-                &peer_string[1..3]
-            };
-
-            if self.blacklist_style {
-                // Check that client isn't part of blacklist.
-                // If so, reject with same error as above.
-                // If not, let the request pass through.
-                if self.list.contains(&client_check.to_string()) {
-                    let failure = AnnounceResponse::failure("Unsupported Client".to_string());
-                    let bencoded = bencode::encode_announce_response(failure);
-                    Either::Right(ok(req.into_response(
-                        HttpResponse::Ok()
-                            .content_type("text/plain")
-                            .body(bencoded)
-                            .into_body(),
-                    )))
-                } else {
-                    Either::Left(self.service.call(req))
-                }
-            } else {
-                // Check that client is part of whitelist.
-                // If so, let the request pass through.
-                // If not, reject with same error as above.
-                if self.list.contains(&client_check.to_string()) {
-                    Either::Left(self.service.call(req))
-                } else {
-                    let failure = AnnounceResponse::failure("Unsupported Client".to_string());
-                    let bencoded = bencode::encode_announce_response(failure);
-                    Either::Right(ok(req.into_response(
-                        HttpResponse::Ok()
-                            .content_type("text/plain")
-                            .body(bencoded)
-                            .into_body(),
-                    )))
-                }
+        // Peer IDs are raw bytes, so they're taken
+        // straight from the query rather than as UTF-8
+        let peer_id = bittorrent::raw_param(req.query_string(), "peer_id").unwrap_or_default();
+
+        match approval.check(&peer_id) {
+            Some(message) if approval.shadow => {
+                info!(
+                    "Would have rejected client {:?}: {}",
+                    String::from_utf8_lossy(&peer_id),
+                    message
+                );
+                Either::Left(self.service.call(req))
+            }
+            Some(message) => {
+                let failure = AnnounceResponse::failure(message);
+                let bencoded = bencode::encode_announce_response(failure);
+                Either::Right(ok(req.into_response(
+                    HttpResponse::Ok()
+                        .content_type("text/plain")
+                        .body(bencoded)
+                        .into_body(),
+                )))
             }
+            None => Either::Left(self.service.call(req)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::ClientRule;
+
+    fn approval(blacklist_style: bool) -> Arc<Approval> {
+        ClientApproval::new(&config::ClientApproval {
+            enabled: true,
+            blacklist_style,
+            client_list: vec!["TR".to_string()],
+            rules: vec![ClientRule {
+                client: Some("qB >= 4.2".to_string()),
+                pattern: None,
+                message: Some("Please upgrade".to_string()),
+            }],
+            ..Default::default()
+        })
+        .unwrap()
+        .current()
+    }
+
+    #[test]
+    fn client_approval_whitelist() {
+        let approval = approval(false);
+
+        assert_eq!(approval.check(b"-qB4250-abcdefghijkl"), None);
+        assert_eq!(approval.check(b"-TR2940-abcdefghijkl"), None);
+        assert_eq!(
+            approval.check(b"-qB4100-abcdefghijkl"),
+            Some("Please upgrade".to_string())
+        );
+        assert_eq!(
+            approval.check(b"-UT3550-abcdefghijkl"),
+            Some(DEFAULT_MESSAGE.to_string())
+        );
+        assert_eq!(approval.check(b"-"), Some(DEFAULT_MESSAGE.to_string()));
+        assert_eq!(approval.check(b""), Some(DEFAULT_MESSAGE.to_string()));
+    }
+
+    #[test]
+    fn client_approval_blacklist() {
+        let approval = approval(true);
+
+        assert_eq!(
+            approval.check(b"-qB4250-abcdefghijkl"),
+            Some("Please upgrade".to_string())
+        );
+        assert_eq!(
+            approval.check(b"-TR2940-abcdefghijkl"),
+            Some(DEFAULT_MESSAGE.to_string())
+        );
+        assert_eq!(approval.check(b"-qB4100-abcdefghijkl"), None);
+        assert_eq!(approval.check(b"x"), None);
+    }
+
+    #[test]
+    fn client_approval_reload() {
+        let approval = ClientApproval::new(&config::ClientApproval::default()).unwrap();
+        assert!(!approval.current().enabled);
+
+        let invalid = config::ClientApproval {
+            enabled: true,
+            rules: vec![ClientRule {
+                client: None,
+                pattern: Some("(".to_string()),
+                message: None,
+            }],
+            ..Default::default()
+        };
+        assert!(approval.reload(&invalid).is_err());
+        assert!(!approval.current().enabled);
+
+        let valid = config::ClientApproval {
+            enabled: true,
+            client_list: vec!["qB".to_string()],
+            ..Default::default()
+        };
+        approval.reload(&valid).unwrap();
+        assert!(approval.current().enabled);
+        assert_eq!(approval.current().check(b"-qB4250-abcdefghijkl"), None);
+    }
+}
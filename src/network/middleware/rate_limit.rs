@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, Either, Ready};
+
+use crate::bencode;
+use crate::bittorrent::AnnounceResponse;
+use crate::network::cidr::Cidr;
+use crate::network::proxy;
+use crate::network::rate_limit::RateLimiter;
+
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+    trusted_proxies: Arc<Vec<Cidr>>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>, trusted_proxies: Vec<Cidr>) -> Self {
+        RateLimit {
+            limiter,
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service,
+            limiter: self.limiter.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: Arc<RateLimiter>,
+    trusted_proxies: Arc<Vec<Cidr>>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let limited = proxy::service_client_ip(&req, &self.trusted_proxies)
+            .and_then(|ip| self.limiter.check(ip).err());
+
+        match limited {
+            Some(retry_in) => {
+                let failure =
+                    AnnounceResponse::retry_later("Rate limit exceeded".to_string(), retry_in);
+                let bencoded = bencode::encode_announce_response(failure);
+                Either::Right(ok(req.into_response(
+                    HttpResponse::Ok()
+                        .content_type("text/plain")
+                        .body(bencoded)
+                        .into_body(),
+                )))
+            }
+            None => Either::Left(self.service.call(req)),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::cmp::Ordering;
+
+use regex::bytes::Regex;
+
+use crate::config::{ClientApproval as ClientApprovalConfig, ClientRule};
+use crate::peer_id::{self, ClientId};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+impl Comparison {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessOrEqual => ordering != Ordering::Greater,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Client {
+        client: String,
+        version: Option<(Comparison, Vec<u32>)>,
+    },
+    Pattern(Regex),
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    matcher: Matcher,
+    pub message: Option<String>,
+}
+
+// How closely a peer ID fits a rule. A client that is named by a
+// rule but falls outside of its version range is told that rule's
+// message when it gets rejected, e.g. to ask for an upgrade.
+#[derive(Debug, PartialEq)]
+pub enum Fit {
+    Match,
+    WrongVersion,
+    NoMatch,
+}
+
+impl Rule {
+    pub fn parse(rule: &ClientRule) -> Result<Rule, String> {
+        let matcher = match (&rule.client, &rule.pattern) {
+            (Some(client), None) => parse_client(client)?,
+            (None, Some(pattern)) => Matcher::Pattern(
+                Regex::new(pattern).map_err(|e| format!("Invalid client pattern: {}", e))?,
+            ),
+            _ => {
+                return Err(format!(
+                    "Client rule needs exactly one of 'client' or 'pattern': {:?}",
+                    rule
+                ))
+            }
+        };
+
+        Ok(Rule {
+            matcher,
+            message: rule.message.clone(),
+        })
+    }
+
+    // Entries of the plain client list are compared with the characters
+    // that follow the first byte of the peer ID: 'qB' for '-qB4250-', or
+    // 'qB4250' when the list is versioned. An entry of any other length
+    // could never be equal to them.
+    fn legacy(entry: &str, versioned: bool) -> Option<Rule> {
+        let width = if versioned { 6 } else { 2 };
+        if entry.len() != width {
+            return None;
+        }
+
+        Some(Rule {
+            matcher: Matcher::Pattern(
+                Regex::new(&format!("(?s-u)^.{}", regex::escape(entry))).unwrap(),
+            ),
+            message: None,
+        })
+    }
+
+    pub fn fit(&self, peer_id: &[u8], decoded: Option<&ClientId>) -> Fit {
+        match &self.matcher {
+            Matcher::Pattern(regex) => {
+                if regex.is_match(peer_id) {
+                    Fit::Match
+                } else {
+                    Fit::NoMatch
+                }
+            }
+            Matcher::Client { client, version } => match decoded {
+                Some(id) if &id.client == client => match version {
+                    Some((comparison, wanted)) => {
+                        if comparison.holds(peer_id::compare_versions(&id.version, wanted)) {
+                            Fit::Match
+                        } else {
+                            Fit::WrongVersion
+                        }
+                    }
+                    None => Fit::Match,
+                },
+                _ => Fit::NoMatch,
+            },
+        }
+    }
+}
+
+// 'qB', 'qB >= 4.2' or 'M=7.10'
+fn parse_client(spec: &str) -> Result<Matcher, String> {
+    let spec = spec.trim();
+    let operators = [
+        (">=", Comparison::GreaterOrEqual),
+        ("<=", Comparison::LessOrEqual),
+        ("!=", Comparison::NotEqual),
+        ("==", Comparison::Equal),
+        (">", Comparison::Greater),
+        ("<", Comparison::Less),
+        ("=", Comparison::Equal),
+    ];
+
+    for (operator, comparison) in operators.iter() {
+        if let Some((client, version)) = spec.split_once(operator) {
+            let version = version
+                .trim()
+                .split('.')
+                .map(|part| part.trim().parse::<u32>())
+                .collect::<Result<Vec<u32>, _>>()
+                .map_err(|_| format!("Invalid version in client rule: {}", spec))?;
+
+            return Ok(Matcher::Client {
+                client: client.trim().to_string(),
+                version: Some((*comparison, version)),
+            });
+        }
+    }
+
+    if spec.is_empty() {
+        return Err("Empty client rule".to_string());
+    }
+
+    Ok(Matcher::Client {
+        client: spec.to_string(),
+        version: None,
+    })
+}
+
+// The plain client list and the rules are combined into a single list
+pub fn compile(config: &ClientApprovalConfig) -> Result<Vec<Rule>, String> {
+    let mut rules = Vec::new();
+    for entry in &config.client_list {
+        match Rule::legacy(entry, config.versioned) {
+            Some(rule) => rules.push(rule),
+            None => warn!(
+                "Client list entry '{}' doesn't fit 'versioned = {}' and never matches",
+                entry, config.versioned
+            ),
+        }
+    }
+
+    for rule in &config.rules {
+        rules.push(Rule::parse(rule)?);
+    }
+
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(client: Option<&str>, pattern: Option<&str>) -> Rule {
+        Rule::parse(&ClientRule {
+            client: client.map(String::from),
+            pattern: pattern.map(String::from),
+            message: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn client_rule_versions() {
+        let rule = rule(Some("qB >= 4.2"), None);
+        let check = |id: &[u8]| rule.fit(id, peer_id::decode(id).as_ref());
+
+        assert_eq!(check(b"-qB4250-abcdefghijkl"), Fit::Match);
+        assert_eq!(check(b"-qB4200-abcdefghijkl"), Fit::Match);
+        assert_eq!(check(b"-qB4190-abcdefghijkl"), Fit::WrongVersion);
+        assert_eq!(check(b"-TR2940-abcdefghijkl"), Fit::NoMatch);
+        assert_eq!(check(b"-q"), Fit::NoMatch);
+    }
+
+    #[test]
+    fn client_rule_patterns() {
+        let rule = rule(None, Some("^-XL00"));
+        assert_eq!(rule.fit(b"-XL0012-abcdefghijkl", None), Fit::Match);
+        assert_eq!(rule.fit(b"-qB4250-abcdefghijkl", None), Fit::NoMatch);
+
+        let legacy = Rule::legacy("qB", false).unwrap();
+        assert_eq!(legacy.fit(b"-qB4250-abcdefghijkl", None), Fit::Match);
+        assert_eq!(legacy.fit(b"-q", None), Fit::NoMatch);
+    }
+
+    #[test]
+    fn client_rule_versioned_list() {
+        let versioned = Rule::legacy("qB4250", true).unwrap();
+        assert_eq!(versioned.fit(b"-qB4250-abcdefghijkl", None), Fit::Match);
+        assert_eq!(versioned.fit(b"-qB4190-abcdefghijkl", None), Fit::NoMatch);
+
+        assert!(Rule::legacy("qB", true).is_none());
+        assert!(Rule::legacy("qB4250", false).is_none());
+    }
+
+    #[test]
+    fn client_rule_invalid() {
+        let invalid = |client: Option<&str>, pattern: Option<&str>| {
+            Rule::parse(&ClientRule {
+                client: client.map(String::from),
+                pattern: pattern.map(String::from),
+                message: None,
+            })
+            .is_err()
+        };
+
+        assert!(invalid(Some("qB >= four"), None));
+        assert!(invalid(None, Some("(")));
+        assert!(invalid(None, None));
+        assert!(invalid(Some("qB"), Some("^-qB")));
+    }
+}
@@ -1,15 +1,35 @@
+pub mod ban;
+pub mod cidr;
+pub mod ip_policy;
 pub mod middleware;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod tls;
 
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
 use crate::bencode;
-use crate::bittorrent::{AnnounceRequest, AnnounceResponse, ScrapeRequest, ScrapeResponse};
+use crate::bittorrent::{AnnounceRequest, AnnounceResponse, Crypto, ScrapeRequest, ScrapeResponse};
+use crate::config::Config;
+use crate::interval;
+use crate::obfuscation::ObfuscatedPeers;
 use crate::state::State;
 use crate::statistics::ReturnedStatistics;
 use crate::util::Event;
 
 pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Responder {
-    let announce_request = AnnounceRequest::new(req.query_string(), req.connection_info().remote());
+    let config = data.config();
+    let remote_ip = proxy::client_ip(&req, &config.network.trusted_proxies);
+    let announce_request = AnnounceRequest::new(req.query_string(), remote_ip);
+    let announce_request = match announce_request {
+        Ok(parsed_req) => resolve_obfuscated(&data, parsed_req).await,
+        Err(failure) => Err(failure),
+    };
+    let announce_request = match announce_request {
+        Ok(parsed_req) => enforce_ip_policy(&data, &config, parsed_req).await,
+        Err(failure) => Err(failure),
+    };
 
     match announce_request {
         Ok(parsed_req) => {
@@ -20,38 +40,18 @@ pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Re
                 // starts or resumes the leeching process
                 Event::Started => {
                     data.peer_store
-                        .put_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
+                        .put_leecher(parsed_req.info_hash.clone(), parsed_req.peer.clone())
                         .await;
                     data.torrent_store
                         .new_leech(parsed_req.info_hash.clone())
                         .await;
 
-                    // Get randomized peer list
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
-
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    // Associate all the requisite data together and
-                    // respond with the bencoded version of the data
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
+                    let bencoded = announce_response(&data, &config, &parsed_req).await;
 
                     let mut stats = data.stats.write().await;
                     stats.add_leech();
                     stats.succ_announce();
 
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
                     HttpResponse::Ok().content_type("text/plain").body(bencoded)
                 }
 
@@ -59,41 +59,29 @@ pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Re
                 Event::Stopped => {
                     // If the peer is present in one set, then it
                     // cannot be present in the other.
-                    let mut stats = data.stats.write().await;
-
-                    if data
-                        .peer_store
-                        .remove_seeder(parsed_req.info_hash.clone(), parsed_req.peer.clone())
-                        .await
                     {
-                        stats.sub_seed();
-                    } else {
-                        data.peer_store
-                            .remove_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
-                            .await;
-                        stats.sub_leech();
+                        let mut stats = data.stats.write().await;
+
+                        if data
+                            .peer_store
+                            .remove_seeder(parsed_req.info_hash.clone(), parsed_req.peer.clone())
+                            .await
+                        {
+                            stats.sub_seed();
+                        } else {
+                            data.peer_store
+                                .remove_leecher(
+                                    parsed_req.info_hash.clone(),
+                                    parsed_req.peer.clone(),
+                                )
+                                .await;
+                            stats.sub_leech();
+                        }
+
+                        stats.succ_announce();
                     }
 
-                    stats.succ_announce();
-
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
-
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
+                    let bencoded = announce_response(&data, &config, &parsed_req).await;
                     HttpResponse::Ok().content_type("text/plain").body(bencoded)
                 }
 
@@ -101,34 +89,18 @@ pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Re
                 // of the data associated with a particular torrent
                 Event::Completed => {
                     data.peer_store
-                        .promote_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
+                        .promote_leecher(parsed_req.info_hash.clone(), parsed_req.peer.clone())
                         .await;
                     data.torrent_store
                         .new_seed(parsed_req.info_hash.clone())
                         .await;
 
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
+                    let bencoded = announce_response(&data, &config, &parsed_req).await;
 
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
                     let mut stats = data.stats.write().await;
                     stats.promote_leech();
                     stats.succ_announce();
 
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
                     HttpResponse::Ok().content_type("text/plain").body(bencoded)
                 }
 
@@ -139,27 +111,10 @@ pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Re
                     // It is intended that a client correctly send its states.
                     // If a client starts out with this event, it will never be added.
                     data.peer_store
-                        .update_peer(parsed_req.info_hash.clone(), parsed_req.peer)
-                        .await;
-
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
+                        .update_peer(parsed_req.info_hash.clone(), parsed_req.peer.clone())
                         .await;
 
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
+                    let bencoded = announce_response(&data, &config, &parsed_req).await;
                     data.stats.write().await.succ_announce();
                     HttpResponse::Ok().content_type("text/plain").body(bencoded)
                 }
@@ -175,6 +130,98 @@ pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Re
     }
 }
 
+// BEP 08: announces that only carry SHA-1(info_hash) are
+// mapped back to the real info hash of a known torrent
+async fn resolve_obfuscated(
+    data: &State,
+    mut parsed_req: AnnounceRequest,
+) -> Result<AnnounceRequest, AnnounceResponse> {
+    if let Some(sha_ih) = &parsed_req.sha_ih {
+        match data.torrent_store.resolve_obfuscated(sha_ih).await {
+            Some(info_hash) => parsed_req.info_hash = info_hash,
+            None => {
+                return Err(AnnounceResponse::failure(
+                    "Unregistered torrent".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(parsed_req)
+}
+
+// Clients asking to be registered under an address that the
+// policy doesn't allow are registered under their real one
+async fn enforce_ip_policy(
+    data: &State,
+    config: &Config,
+    mut parsed_req: AnnounceRequest,
+) -> Result<AnnounceRequest, AnnounceResponse> {
+    if let Some(requested) = parsed_req.ip {
+        if !ip_policy::permits_override(&config.network, parsed_req.remote_ip, requested) {
+            warn!(
+                "Rejected ip override to {} from {:?}",
+                requested, parsed_req.remote_ip
+            );
+            data.stats.write().await.reject_ip_override();
+
+            if !parsed_req.discard_ip_override() {
+                return Err(AnnounceResponse::failure(
+                    "IP address override not allowed".to_string(),
+                ));
+            }
+        }
+    }
+
+    Ok(parsed_req)
+}
+
+// Every event ends the same way: grab a randomized peer list and the
+// swarm totals, associate all the requisite data together, and
+// respond with the bencoded version of the data
+async fn announce_response(data: &State, config: &Config, parsed_req: &AnnounceRequest) -> Vec<u8> {
+    let numwant = parsed_req
+        .numwant
+        .unwrap_or(config.bt.default_numwant)
+        .min(config.bt.max_numwant);
+    let (peers, peers6) = data
+        .peer_store
+        .get_peers(parsed_req.info_hash.clone(), numwant, parsed_req.crypto)
+        .await;
+
+    let (complete, incomplete) = data
+        .torrent_store
+        .get_announce_stats(parsed_req.info_hash.clone())
+        .await;
+
+    let request_rate = data.stats.read().await.request_rate();
+    let (interval, min_interval) =
+        interval::announce_interval(config, complete + incomplete, request_rate);
+
+    let mut response =
+        AnnounceResponse::new(interval, complete, incomplete, peers, peers6).unwrap();
+    response.min_interval = min_interval;
+
+    // Clients that know about encryption also want to know which peers insist on it
+    response.crypto_flags = parsed_req.crypto != Crypto::None;
+
+    // Clients hiding the info hash also expect an encrypted peer list
+    if parsed_req.sha_ih.is_some() {
+        response.obfuscated = Some(ObfuscatedPeers::new(
+            &parsed_req.info_hash,
+            response.peersv4_as_compact(),
+            response.peersv6_as_compact(),
+        ));
+    }
+
+    // BEP 24: let clients behind a NAT know which address we see
+    if config.bt.external_ip {
+        response.external_ip = parsed_req.remote_ip;
+    }
+
+    bencode::encode_announce_response(response)
+}
+
 pub async fn parse_scrape(data: web::Data<State>, req: HttpRequest) -> impl Responder {
     let scrape_request = ScrapeRequest::new(req.query_string());
     match scrape_request {
@@ -200,7 +247,9 @@ pub async fn parse_scrape(data: web::Data<State>, req: HttpRequest) -> impl Resp
 
 pub async fn get_stats(data: web::Data<State>) -> impl Responder {
     let global_stats = data.stats.read().await;
-    let stats = ReturnedStatistics::new(&global_stats);
+    let mut storage = data.storage.read().clone();
+    storage.dirty_torrents = data.torrent_store.dirty_count();
+    let stats = ReturnedStatistics::new(&global_stats, storage);
     web::Json(stats)
 }
 
@@ -216,6 +265,7 @@ mod tests {
     use crate::storage::{Torrent, TorrentRecords, TorrentStore};
 
     #[actix_rt::test]
+    #[allow(clippy::redundant_closure)]
     async fn index_get_not_allowed() {
         let config = Config::default();
         let torrent_store = TorrentStore::new(TorrentRecords::new());
@@ -245,6 +295,7 @@ mod tests {
     }
 
     #[actix_rt::test]
+    #[allow(clippy::redundant_closure)]
     async fn announce_get_malformed() {
         let config = Config::default();
         let torrent_store = TorrentStore::new(TorrentRecords::new());
@@ -275,6 +326,7 @@ mod tests {
     }
 
     #[actix_rt::test]
+    #[allow(clippy::redundant_closure)]
     async fn scrape_get_malformed() {
         let config = Config::default();
         let torrent_store = TorrentStore::new(TorrentRecords::new());
@@ -305,6 +357,56 @@ mod tests {
     }
 
     #[actix_rt::test]
+    async fn announce_then_scrape_in_memory_mode() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new()).register_unknown();
+        let stores = web::Data::new(State::new(config, torrent_store));
+
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                )
+                .service(
+                    web::scope("scrape")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_scrape)),
+                ),
+        )
+        .await;
+
+        let announces = [
+            ("ABCDEFGHIJKLMNOPQRST", "started"),
+            ("BCDEFGHIJKLMNOPQRSTU", "started"),
+            ("BCDEFGHIJKLMNOPQRSTU", "completed"),
+        ];
+        for (peer_id, event) in announces.iter() {
+            let uri = format!(
+                "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id={}&port=6881\
+                 &uploaded=0&downloaded=0&left=0&event={}&compact=1",
+                peer_id, event
+            );
+            let req = test::TestRequest::with_uri(&uri)
+                .peer_addr("127.0.0.1:40000".parse().unwrap())
+                .to_request();
+            let resp = test::read_response(&mut app, req).await;
+            assert!(!resp.starts_with(b"d14:failure_reason"));
+        }
+
+        let proper_resp =
+            "d5:filesd20:A1B2C3D4E5F6G7H8I9J0d8:completei1e10:downloadedi0e10:incompletei1eeee"
+                .as_bytes();
+        let req =
+            test::TestRequest::with_uri("/scrape?info_hash=A1B2C3D4E5F6G7H8I9J0").to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    #[allow(clippy::redundant_closure)]
     async fn scrape_get_success() {
         let config = Config::default();
         let torrent_store = TorrentStore::new(TorrentRecords::new());
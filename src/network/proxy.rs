@@ -0,0 +1,168 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use actix_web::HttpRequest;
+
+use crate::network::cidr::{canonical, Cidr};
+
+// Works out the address of the client that sent a request. Forwarding
+// headers are only honored when the connection itself comes from a trusted
+// proxy, as anyone can send them. When they are, the chain of hops is walked
+// from the right (nearest) to the left, and the first address that isn't one
+// of our own proxies is taken to be the client.
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let peer = canonical(req.peer_addr()?.ip());
+    resolve(peer, req.headers(), trusted_proxies)
+}
+
+// The same, for requests that haven't reached a handler yet
+pub fn service_client_ip(req: &ServiceRequest, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let peer = canonical(req.peer_addr()?.ip());
+    resolve(peer, req.headers(), trusted_proxies)
+}
+
+fn resolve(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    if !is_trusted(peer, trusted_proxies) {
+        return Some(peer);
+    }
+
+    let mut client = peer;
+    for hop in forwarded_hops(headers).iter().rev() {
+        match hop.as_deref().and_then(parse_addr) {
+            Some(addr) => {
+                client = canonical(addr);
+                if !is_trusted(client, trusted_proxies) {
+                    break;
+                }
+            }
+            // An obfuscated or garbled hop can't be followed any further,
+            // so the last proxy that could be identified has to do
+            None => break,
+        }
+    }
+
+    Some(client)
+}
+
+pub fn is_trusted(addr: IpAddr, trusted_proxies: &[Cidr]) -> bool {
+    trusted_proxies.iter().any(|cidr| cidr.contains(addr))
+}
+
+// RFC 7239 'Forwarded' takes precedence over the de facto 'X-Forwarded-For'.
+// Hops are returned in order, from the original client to the nearest proxy.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<String>> {
+    let mut hops = Vec::new();
+
+    for value in headers.get_all("forwarded") {
+        let value = match value.to_str() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        for element in value.split(',') {
+            let node = element.split(';').find_map(|pair| {
+                let mut kv = pair.trim().splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if k.eq_ignore_ascii_case("for") => {
+                        Some(v.trim().trim_matches('"').to_string())
+                    }
+                    _ => None,
+                }
+            });
+            hops.push(node);
+        }
+    }
+
+    if !hops.is_empty() {
+        return hops;
+    }
+
+    for value in headers.get_all("x-forwarded-for") {
+        if let Ok(value) = value.to_str() {
+            hops.extend(value.split(',').map(|hop| Some(hop.trim().to_string())));
+        }
+    }
+
+    hops
+}
+
+// Addresses may come with or without a port, and IPv6
+// addresses with a port are wrapped in brackets
+pub fn parse_addr(addr: &str) -> Option<IpAddr> {
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        return Some(socket_addr.ip());
+    }
+
+    addr.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    fn trusted() -> Vec<Cidr> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn proxy_headers_ignored_from_untrusted_peer() {
+        let req = TestRequest::default()
+            .peer_addr("203.0.113.5:40000".parse().unwrap())
+            .header("X-Forwarded-For", "198.51.100.1")
+            .to_http_request();
+
+        assert_eq!(
+            client_ip(&req, &trusted()),
+            Some("203.0.113.5".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn proxy_rightmost_untrusted_hop() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:40000".parse().unwrap())
+            .header("X-Forwarded-For", "1.1.1.1, 198.51.100.1, 10.0.0.2")
+            .to_http_request();
+
+        assert_eq!(
+            client_ip(&req, &trusted()),
+            Some("198.51.100.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn proxy_forwarded_header() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:40000".parse().unwrap())
+            .header(
+                "Forwarded",
+                "for=192.0.2.60;proto=http, for=\"[2001:db8:cafe::17]:4711\"",
+            )
+            .header("X-Forwarded-For", "198.51.100.1")
+            .to_http_request();
+
+        assert_eq!(
+            client_ip(&req, &trusted()),
+            Some("2001:db8:cafe::17".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn proxy_unparseable_hop() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:40000".parse().unwrap())
+            .header("Forwarded", "for=unknown, for=10.0.0.7")
+            .to_http_request();
+
+        assert_eq!(
+            client_ip(&req, &trusted()),
+            Some("10.0.0.7".parse().unwrap())
+        );
+    }
+}
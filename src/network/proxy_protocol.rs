@@ -0,0 +1,308 @@
+// HAProxy PROXY protocol, versions 1 and 2
+// https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+//
+// TCP load balancers prepend a small header to each connection that carries
+// the address of the original client. When enabled, every accepted connection
+// must start with such a header; its source address then takes the place of
+// the socket's peer address for everything further down the line. Only
+// connections from trusted proxies are accepted, as anyone else could
+// claim to be whoever they like.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol, Request, Response};
+use actix_rt::net::TcpStream;
+use actix_rt::time::timeout;
+use actix_server::Server;
+use actix_service::{map_config, pipeline_factory, IntoServiceFactory, Service, ServiceFactory};
+use actix_web::body::MessageBody;
+use actix_web::dev::AppConfig;
+use actix_web::Error;
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_rustls::TlsAcceptor;
+
+use crate::network::cidr::Cidr;
+use crate::network::proxy;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// A client that can't produce a header in this time is not a load balancer
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Reads exactly one PROXY header off of the stream, leaving the request
+// that follows untouched. The source address is only returned for proxied
+// TCP connections; health checks (LOCAL/UNKNOWN) carry no client address.
+pub async fn read_header<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    io.read_exact(&mut start).await?;
+
+    if start == V1_PREFIX {
+        read_v1(io).await
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(io).await
+    } else {
+        Err(invalid("Missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+
+    // The header has no length field, so it has to be read up
+    // to the CRLF without consuming anything beyond it
+    while !line.ends_with(b"\r\n") {
+        if line.len() + V1_PREFIX.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[0] {
+        "UNKNOWN" => Ok(None),
+        "TCP4" | "TCP6" if fields.len() == 5 => {
+            let ip = fields[1]
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("Invalid PROXY v1 source address"))?;
+            let port = fields[3]
+                .parse::<u16>()
+                .map_err(|_| invalid("Invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Option<SocketAddr>> {
+    // Remainder of the signature, version/command, family and length
+    let mut rest = [0u8; 10];
+    io.read_exact(&mut rest).await?;
+
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("Invalid PROXY v2 signature"));
+    }
+
+    let (version_command, family) = (rest[6], rest[7]);
+    let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+
+    let mut payload = vec![0u8; len];
+    io.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+
+    match version_command & 0x0F {
+        // LOCAL connections come from the proxy itself
+        0x0 => Ok(None),
+        0x1 => parse_v2_addresses(family, &payload),
+        _ => Err(invalid("Unsupported PROXY v2 command")),
+    }
+}
+
+fn parse_v2_addresses(family: u8, payload: &[u8]) -> io::Result<Option<SocketAddr>> {
+    // The high nibble is the address family, the low one the transport
+    match family >> 4 {
+        0x1 if payload.len() >= 12 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&payload[..4]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        0x2 if payload.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC and AF_UNIX carry nothing useful
+        0x0 | 0x3 => Ok(None),
+        _ => Err(invalid("Truncated PROXY v2 address block")),
+    }
+}
+
+fn from_trusted(peer_addr: Option<SocketAddr>, trusted_proxies: &[Cidr]) -> bool {
+    peer_addr.is_some_and(|addr| proxy::is_trusted(addr.ip(), trusted_proxies))
+}
+
+async fn accept(
+    mut io: TcpStream,
+    trusted_proxies: &[Cidr],
+) -> Result<(TcpStream, Option<SocketAddr>), DispatchError> {
+    let peer_addr = io.peer_addr().ok();
+
+    if !from_trusted(peer_addr, trusted_proxies) {
+        debug!("Dropping connection from untrusted {:?}", peer_addr);
+        return Err(DispatchError::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "PROXY protocol header from an untrusted address",
+        )));
+    }
+
+    let source = match timeout(HEADER_TIMEOUT, read_header(&mut io)).await {
+        Ok(Ok(source)) => source,
+        Ok(Err(e)) => {
+            debug!("Dropping connection from {:?}: {}", peer_addr, e);
+            return Err(DispatchError::Io(e));
+        }
+        Err(_) => {
+            return Err(DispatchError::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for PROXY protocol header",
+            )))
+        }
+    };
+
+    Ok((io, source.or(peer_addr)))
+}
+
+// actix-web's HttpServer always takes the peer address from the socket, so
+// listeners that speak the PROXY protocol are put together here instead,
+// with the header being read before the connection is handed to the app.
+// Signals are left to the caller, like for the regular HttpServer.
+pub fn server<F, I, S, B>(
+    factory: F,
+    binding: &str,
+    tls: Option<(&str, Arc<ServerConfig>)>,
+    trusted_proxies: Vec<Cidr>,
+    shutdown_timeout: u64,
+) -> io::Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let trusted_proxies = Arc::new(trusted_proxies);
+    let plain_trusted_proxies = trusted_proxies.clone();
+    let plain_factory = factory.clone();
+    let mut builder = Server::build()
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout)
+        .bind("tyto-proxy-protocol", binding, move || {
+            let trusted_proxies = plain_trusted_proxies.clone();
+
+            pipeline_factory(move |io: TcpStream| {
+                let trusted_proxies = trusted_proxies.clone();
+                async move {
+                    let (io, source) = accept(io, &trusted_proxies).await?;
+                    Ok((io, Protocol::Http1, source))
+                }
+            })
+            .and_then(
+                HttpService::build().finish(map_config(plain_factory(), |_| AppConfig::default())),
+            )
+        })?;
+
+    if let Some((tls_binding, tls_config)) = tls {
+        let acceptor = TlsAcceptor::from(tls_config);
+
+        builder = builder.bind("tyto-proxy-protocol-tls", tls_binding, move || {
+            let acceptor = acceptor.clone();
+            let trusted_proxies = trusted_proxies.clone();
+
+            pipeline_factory(move |io: TcpStream| {
+                let acceptor = acceptor.clone();
+                let trusted_proxies = trusted_proxies.clone();
+                async move {
+                    let (io, source) = accept(io, &trusted_proxies).await?;
+                    let io = acceptor.accept(io).await.map_err(DispatchError::Io)?;
+                    Ok((io, Protocol::Http1, source))
+                }
+            })
+            .and_then(HttpService::build().finish(map_config(factory(), |_| AppConfig::default())))
+        })?;
+    }
+
+    Ok(builder.run())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_protocol_only_from_trusted() {
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        assert!(from_trusted(
+            Some("10.1.2.3:4000".parse().unwrap()),
+            &trusted
+        ));
+        assert!(!from_trusted(
+            Some("192.0.2.1:4000".parse().unwrap()),
+            &trusted
+        ));
+        assert!(!from_trusted(None, &trusted));
+        assert!(!from_trusted(Some("10.1.2.3:4000".parse().unwrap()), &[]));
+    }
+
+    #[actix_rt::test]
+    async fn proxy_protocol_v1_tcp4() {
+        let mut input = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        let source = read_header(&mut input).await.unwrap();
+
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(input, &b"GET / HTTP/1.1\r\n"[..]);
+    }
+
+    #[actix_rt::test]
+    async fn proxy_protocol_v1_unknown() {
+        let mut input = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut input).await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn proxy_protocol_v2_tcp6() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0x00, 36]);
+        header.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        header.extend_from_slice(b"GET /");
+
+        let mut input = &header[..];
+        let source = read_header(&mut input).await.unwrap();
+
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(input, &b"GET /"[..]);
+    }
+
+    #[actix_rt::test]
+    async fn proxy_protocol_v2_local() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+
+        let mut input = &header[..];
+        assert_eq!(read_header(&mut input).await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn proxy_protocol_missing_header() {
+        let mut input = &b"GET /announce HTTP/1.1\r\n"[..];
+        assert!(read_header(&mut input).await.is_err());
+    }
+}
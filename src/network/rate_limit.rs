@@ -0,0 +1,157 @@
+use std::net::IpAddr;
+use std::time::Instant;
+
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+
+use crate::config::Limit;
+use crate::network::cidr::{canonical, mask};
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// A token bucket per client. Buckets are kept in two generations: once
+// the current one holds half of 'max_clients' addresses, it becomes the
+// previous one and the old previous generation is dropped. Clients seen
+// since then are moved back into the current generation, so whoever has
+// been quiet the longest is forgotten first, and memory stays bounded
+// no matter how many addresses a flood comes from.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    generation_size: usize,
+    buckets: Mutex<Generations>,
+}
+
+#[derive(Default)]
+struct Generations {
+    current: HashMap<IpAddr, Bucket>,
+    previous: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit, max_clients: usize) -> RateLimiter {
+        RateLimiter {
+            rate: limit.rate,
+            burst: f64::from(limit.burst),
+            generation_size: (max_clients / 2).max(1),
+            buckets: Mutex::new(Generations::default()),
+        }
+    }
+
+    // Takes a token from the client's bucket. If there are none left,
+    // the number of seconds until the next one is available is returned.
+    pub fn check(&self, addr: IpAddr) -> Result<(), u64> {
+        let key = client_key(addr);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+
+        let bucket = match buckets.previous.remove(&key) {
+            Some(bucket) => bucket,
+            None => match buckets.current.remove(&key) {
+                Some(bucket) => bucket,
+                None => Bucket {
+                    tokens: self.burst,
+                    last_refill: now,
+                },
+            },
+        };
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        let mut bucket = Bucket {
+            tokens: (bucket.tokens + elapsed * self.rate).min(self.burst),
+            last_refill: now,
+        };
+
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+        } else {
+            Err(u64::from(u32::MAX))
+        };
+
+        if buckets.current.len() >= self.generation_size {
+            buckets.previous = std::mem::take(&mut buckets.current);
+        }
+        buckets.current.insert(key, bucket);
+
+        result
+    }
+
+    pub fn tracked_clients(&self) -> usize {
+        let buckets = self.buckets.lock();
+        buckets.current.len() + buckets.previous.len()
+    }
+}
+
+// IPv6 clients usually get a whole /64, so limiting single
+// addresses would let them pick a fresh one for every request
+fn client_key(addr: IpAddr) -> IpAddr {
+    match canonical(addr) {
+        addr @ IpAddr::V4(_) => addr,
+        addr @ IpAddr::V6(_) => mask(addr, 64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_burst_and_retry() {
+        let limiter = RateLimiter::new(
+            Limit {
+                rate: 0.5,
+                burst: 3,
+            },
+            100,
+        );
+        let client = "203.0.113.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(limiter.check(client).is_ok());
+        }
+        assert_eq!(limiter.check(client), Err(2));
+
+        // Other clients have their own buckets
+        assert!(limiter.check("203.0.113.2".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_ipv6_prefix() {
+        let limiter = RateLimiter::new(
+            Limit {
+                rate: 1.0,
+                burst: 1,
+            },
+            100,
+        );
+
+        assert!(limiter.check("2001:db8:1:1::1".parse().unwrap()).is_ok());
+        assert!(limiter.check("2001:db8:1:1::2".parse().unwrap()).is_err());
+        assert!(limiter.check("2001:db8:1:2::1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_bounded_memory() {
+        let limiter = RateLimiter::new(
+            Limit {
+                rate: 1.0,
+                burst: 1,
+            },
+            100,
+        );
+
+        for i in 0..10_000u32 {
+            let _ = limiter.check(IpAddr::V4(i.into()));
+        }
+        assert!(limiter.tracked_clients() <= 100);
+
+        // Recently seen clients are still limited
+        assert!(limiter.check(IpAddr::V4(9_999u32.into())).is_err());
+    }
+}
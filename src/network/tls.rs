@@ -0,0 +1,121 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::RwLock;
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{NoClientAuth, ResolvesServerCert, ServerConfig, SignatureScheme};
+
+// Certificates are resolved on every handshake rather than being baked into
+// the server configuration, which allows them to be swapped out (e.g. after
+// a renewal) without restarting the tracker or dropping connections.
+pub struct CertificateResolver {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<LoadedCertificate>,
+}
+
+struct LoadedCertificate {
+    key: CertifiedKey,
+    modified: Option<SystemTime>,
+}
+
+impl CertificateResolver {
+    pub fn new(cert_path: String, key_path: String) -> io::Result<CertificateResolver> {
+        let current = load_certificate(&cert_path, &key_path)?;
+
+        Ok(CertificateResolver {
+            cert_path,
+            key_path,
+            current: RwLock::new(current),
+        })
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = self.clone();
+        config
+    }
+
+    // Reloads the certificate and key if either file has changed on disk.
+    // On failure, the previous certificate remains in use.
+    pub fn reload(&self) -> io::Result<bool> {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        if modified.is_some() && modified == self.current.read().modified {
+            return Ok(false);
+        }
+
+        let loaded = load_certificate(&self.cert_path, &self.key_path)?;
+        *self.current.write() = loaded;
+
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(
+        &self,
+        _server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        Some(self.current.read().key.clone())
+    }
+}
+
+// The newer of the two modification times is
+// used so that a change to either file is noticed
+fn last_modified(cert_path: &str, key_path: &str) -> Option<SystemTime> {
+    let cert = fs::metadata(cert_path).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(key_path).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn load_certificate(cert_path: &str, key_path: &str) -> io::Result<LoadedCertificate> {
+    let modified = last_modified(cert_path, key_path);
+
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid(format!("Could not parse certificates in {}", cert_path)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificates found in {}", cert_path)));
+    }
+
+    // Keys may be either PKCS#8 or plain RSA
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| invalid(format!("Could not parse private key in {}", key_path)))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| invalid(format!("Could not parse private key in {}", key_path)))?;
+    }
+
+    let key = match keys.first() {
+        Some(key) => sign::any_supported_type(key)
+            .map_err(|_| invalid(format!("Unsupported private key type in {}", key_path)))?,
+        None => return Err(invalid(format!("No private key found in {}", key_path))),
+    };
+
+    Ok(LoadedCertificate {
+        key: CertifiedKey::new(certs, Arc::new(key)),
+        modified,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_missing_certificate() {
+        let resolver = CertificateResolver::new(
+            "/nonexistent/cert.pem".to_string(),
+            "/nonexistent/key.pem".to_string(),
+        );
+
+        assert!(resolver.is_err());
+    }
+}
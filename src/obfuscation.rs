@@ -0,0 +1,134 @@
+// BEP 08: Tracker Peer Obfuscation
+// http://bittorrent.org/beps/bep_0008.html
+//
+// Clients may send 'sha_ih', the SHA-1 of the info hash, in place of the
+// info hash itself so that it never travels in the clear. As only parties
+// that already know the info hash can derive the key, the peer list in
+// the response is then RC4-encrypted with a key derived from it.
+
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+
+// The start of an RC4 keystream is notoriously weak and is thrown away
+const RC4_DROP: usize = 768;
+const IV_LEN: usize = 20;
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.state[self.i as usize]);
+        self.state.swap(self.i as usize, self.j as usize);
+
+        let idx = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+        self.state[idx as usize]
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte ^= self.next_byte();
+        }
+    }
+}
+
+// Both compact peer strings are run through the same keystream,
+// 'peers' first and 'peers6' after it, with a fresh IV per response
+#[derive(Debug)]
+pub struct ObfuscatedPeers {
+    pub iv: Vec<u8>,
+    pub peers: Vec<u8>,
+    pub peers6: Vec<u8>,
+}
+
+impl ObfuscatedPeers {
+    pub fn new(info_hash: &str, peers: Vec<u8>, peers6: Vec<u8>) -> ObfuscatedPeers {
+        let mut iv = vec![0u8; IV_LEN];
+        rand::thread_rng().fill_bytes(&mut iv);
+
+        ObfuscatedPeers::with_iv(info_hash, iv, peers, peers6)
+    }
+
+    // RC4 is symmetric, so this also decrypts with a known IV
+    pub fn with_iv(
+        info_hash: &str,
+        iv: Vec<u8>,
+        mut peers: Vec<u8>,
+        mut peers6: Vec<u8>,
+    ) -> ObfuscatedPeers {
+        let mut cipher = Rc4::new(&sha1(&[info_hash.as_bytes(), &iv].concat()));
+
+        let mut discard = [0u8; RC4_DROP];
+        cipher.apply(&mut discard);
+
+        cipher.apply(&mut peers);
+        cipher.apply(&mut peers6);
+
+        ObfuscatedPeers { iv, peers, peers6 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rc4_known_vector() {
+        // From RFC 6229, key 0x0102030405, first 16 bytes of keystream
+        let mut data = [0u8; 16];
+        Rc4::new(&[1, 2, 3, 4, 5]).apply(&mut data);
+
+        assert_eq!(
+            data,
+            [
+                0xb2, 0x39, 0x63, 0x05, 0xf0, 0x3d, 0xc0, 0x27, 0xcc, 0xc3, 0x52, 0x4a, 0x0a, 0x11,
+                0x18, 0xa8
+            ]
+        );
+    }
+
+    #[test]
+    fn obfuscated_peers_round_trip() {
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0";
+        let peers = vec![127, 0, 0, 1, 0x1a, 0xe1];
+        let peers6 = vec![0u8; 18];
+
+        let obfuscated = ObfuscatedPeers::new(info_hash, peers.clone(), peers6.clone());
+        assert_ne!(obfuscated.peers, peers);
+
+        // RC4 is symmetric, so running it again with the same IV decrypts
+        let decrypted = ObfuscatedPeers::with_iv(
+            info_hash,
+            obfuscated.iv.clone(),
+            obfuscated.peers,
+            obfuscated.peers6,
+        );
+        assert_eq!(decrypted.peers, peers);
+        assert_eq!(decrypted.peers6, peers6);
+    }
+}
@@ -0,0 +1,176 @@
+// Decoding of the client name and version out of peer IDs
+// https://wiki.theory.org/BitTorrentSpecification#peer_id
+//
+// There is no single format, but three conventions cover nearly
+// every client in the wild:
+//   Azureus  '-qB4250-' followed by random bytes
+//   Shadow   'S58B-----' followed by random bytes
+//   Mainline 'M4-3-6--' followed by random bytes
+
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    Azureus,
+    Shadow,
+    Mainline,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientId {
+    pub style: Style,
+    pub client: String,
+    pub version: Vec<u32>,
+}
+
+pub fn decode(peer_id: &[u8]) -> Option<ClientId> {
+    decode_azureus(peer_id)
+        .or_else(|| decode_mainline(peer_id))
+        .or_else(|| decode_shadow(peer_id))
+}
+
+fn decode_azureus(peer_id: &[u8]) -> Option<ClientId> {
+    if peer_id.len() < 8 || peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+
+    let client = &peer_id[1..3];
+    if !client.iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+
+    // Most clients use one digit per version component,
+    // some use letters for components past nine
+    let version = peer_id[3..7]
+        .iter()
+        .map(|&c| (c as char).to_digit(36))
+        .collect::<Option<Vec<u32>>>()?;
+
+    Some(ClientId {
+        style: Style::Azureus,
+        client: String::from_utf8_lossy(client).into_owned(),
+        version,
+    })
+}
+
+fn decode_mainline(peer_id: &[u8]) -> Option<ClientId> {
+    if peer_id.len() < 8 || !peer_id[0].is_ascii_uppercase() {
+        return None;
+    }
+
+    let rest = &peer_id[1..8];
+    if !rest.iter().all(|&c| c.is_ascii_digit() || c == b'-') {
+        return None;
+    }
+
+    let rest = std::str::from_utf8(rest).ok()?;
+    let version = rest
+        .split('-')
+        .take_while(|part| !part.is_empty())
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+
+    // Components are always terminated by a dash, so a well-formed
+    // ID leaves at least one empty part after splitting
+    if version.len() < 2 || !rest.contains("--") && !rest.ends_with('-') {
+        return None;
+    }
+
+    Some(ClientId {
+        style: Style::Mainline,
+        client: (peer_id[0] as char).to_string(),
+        version,
+    })
+}
+
+// Shadow's version characters map 0-9, A-Z, a-z, '.' and '-' to 0 through 63
+fn shadow_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some(u32::from(c - b'0')),
+        b'A'..=b'Z' => Some(u32::from(c - b'A') + 10),
+        b'a'..=b'z' => Some(u32::from(c - b'a') + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+fn decode_shadow(peer_id: &[u8]) -> Option<ClientId> {
+    if peer_id.len() < 9 || !peer_id[0].is_ascii_alphanumeric() || &peer_id[6..9] != b"---" {
+        return None;
+    }
+
+    let version = peer_id[1..6]
+        .iter()
+        .take_while(|&&c| c != b'-')
+        .map(|&c| shadow_digit(c))
+        .collect::<Option<Vec<u32>>>()?;
+
+    if version.is_empty() {
+        return None;
+    }
+
+    Some(ClientId {
+        style: Style::Shadow,
+        client: (peer_id[0] as char).to_string(),
+        version,
+    })
+}
+
+// Versions are compared component by component, with missing
+// components counting as zero, so that 4.2 == 4.2.0.0
+pub fn compare_versions(a: &[u32], b: &[u32]) -> Ordering {
+    let len = a.len().max(b.len());
+    for i in 0..len {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        match x.cmp(&y) {
+            Ordering::Equal => continue,
+            other => return other,
+        }
+    }
+
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_id_azureus() {
+        let id = decode(b"-qB4250-abcdefghijkl").unwrap();
+        assert_eq!(id.style, Style::Azureus);
+        assert_eq!(id.client, "qB");
+        assert_eq!(id.version, vec![4, 2, 5, 0]);
+    }
+
+    #[test]
+    fn peer_id_mainline() {
+        let id = decode(b"M7-10-1--abcdefghijk").unwrap();
+        assert_eq!(id.style, Style::Mainline);
+        assert_eq!(id.client, "M");
+        assert_eq!(id.version, vec![7, 10, 1]);
+    }
+
+    #[test]
+    fn peer_id_shadow() {
+        let id = decode(b"S58B-----abcdefghijk").unwrap();
+        assert_eq!(id.style, Style::Shadow);
+        assert_eq!(id.client, "S");
+        assert_eq!(id.version, vec![5, 8, 11]);
+    }
+
+    #[test]
+    fn peer_id_unknown_and_short() {
+        assert_eq!(decode(b""), None);
+        assert_eq!(decode(b"-qB"), None);
+        assert_eq!(decode(b"\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09"), None);
+    }
+
+    #[test]
+    fn peer_id_version_ordering() {
+        assert_eq!(compare_versions(&[4, 2], &[4, 2, 0, 0]), Ordering::Equal);
+        assert_eq!(compare_versions(&[4, 1, 9], &[4, 2]), Ordering::Less);
+        assert_eq!(compare_versions(&[10], &[9, 9]), Ordering::Greater);
+    }
+}
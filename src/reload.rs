@@ -0,0 +1,121 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_rt::time::interval;
+use actix_web::web;
+
+use crate::config::Config;
+use crate::network::middleware::ClientApproval;
+use crate::state::State;
+use crate::storage::backend::TorrentBackend;
+use crate::storage::janitor;
+
+// Swaps in a new configuration without a restart. Settings that are only
+// read when the server starts are left alone and reported instead.
+pub struct Reloader {
+    path: String,
+    strict: bool,
+    state: web::Data<State>,
+    client_approval: ClientApproval,
+    backend: Arc<dyn TorrentBackend>,
+    modified: Option<SystemTime>,
+}
+
+impl Reloader {
+    pub fn new(
+        path: String,
+        strict: bool,
+        state: web::Data<State>,
+        client_approval: ClientApproval,
+        backend: Arc<dyn TorrentBackend>,
+    ) -> Reloader {
+        Reloader {
+            modified: last_modified(&path),
+            path,
+            strict,
+            state,
+            client_approval,
+            backend,
+        }
+    }
+
+    // Nothing is changed unless the whole file is valid
+    pub async fn reload(&self) -> Result<Vec<&'static str>, String> {
+        let new = Config::read(&self.path, self.strict)?;
+        let (config, pending) = self.state.config().reloaded(new);
+
+        self.client_approval.reload(&config.client_approval)?;
+        log::set_max_level(config.log.level_filter()?);
+
+        // Bans from the database are read apart from request handling
+        let config = Arc::new(config);
+        match janitor::load_bans(config.clone(), self.backend.clone()).await {
+            Ok(bans) => {
+                self.state.bans.replace(bans);
+            }
+            Err(e) => error!("Could not reload bans: {}", e),
+        }
+
+        self.state.replace_config(Config::clone(&config));
+
+        Ok(pending)
+    }
+
+    async fn reload_and_report(&self) {
+        match self.reload().await {
+            Ok(pending) => {
+                info!("Reloaded configuration from {}", self.path);
+                for setting in pending {
+                    warn!("Changes to '{}' require a restart to take effect", setting);
+                }
+            }
+            Err(e) => error!("Could not reload configuration: {}", e),
+        }
+    }
+
+    fn changed_on_disk(&mut self) -> bool {
+        let modified = last_modified(&self.path);
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            return true;
+        }
+
+        false
+    }
+
+    // Reloads on SIGHUP and, if enabled, whenever the file changes
+    pub async fn run(mut self) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Could not listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+
+        let reload = self.state.config().reload.clone();
+        let mut watch = interval(Duration::new(reload.watch_interval.max(1), 0));
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading configuration...");
+                    self.changed_on_disk();
+                    self.reload_and_report().await;
+                }
+                _ = watch.tick(), if reload.watch => {
+                    if self.changed_on_disk() {
+                        info!("Configuration file changed, reloading...");
+                        self.reload_and_report().await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn last_modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,186 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_rt::time::{delay_for, timeout};
+use futures::future::join;
+
+use crate::state::State;
+use crate::storage::backend::TorrentBackend;
+use crate::storage::janitor;
+
+// Waits for a signal to stop on. Returns whether requests that are
+// in progress should be allowed to finish, which SIGQUIT skips.
+pub async fn signalled() -> io::Result<bool> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut quit = signal(SignalKind::quit())?;
+
+    let graceful = tokio::select! {
+        _ = terminate.recv() => {
+            info!("Received SIGTERM, finishing requests in progress...");
+            true
+        }
+        _ = interrupt.recv() => {
+            info!("Received SIGINT, finishing requests in progress...");
+            true
+        }
+        _ = quit.recv() => {
+            info!("Received SIGQUIT, stopping right away...");
+            false
+        }
+    };
+
+    Ok(graceful)
+}
+
+// The last flush and snapshot run side by side once the server has
+// stopped. Returns whether everything that should be saved was saved.
+pub async fn persist(state: &State, backend: Arc<dyn TorrentBackend>) -> bool {
+    let config = state.config();
+    let limit = Duration::new(config.shutdown.persist_timeout, 0);
+
+    let flush = async {
+        if !backend.persistent() {
+            return true;
+        }
+
+        // The janitor has been stopped by now, but a flush it started
+        // may still be running and has to end before the last one
+        let flush = async {
+            while state.torrent_store.is_flushing() {
+                delay_for(Duration::from_millis(100)).await;
+            }
+            janitor::flush_torrents(state, backend.clone()).await
+        };
+
+        match timeout(limit, flush).await {
+            Ok(Ok(num_torrents)) => {
+                info!("Flushed {} torrents to {}", num_torrents, backend.name());
+                true
+            }
+            Ok(Err(e)) => {
+                error!("Could not flush torrents: {}", e);
+                false
+            }
+            Err(_) => {
+                error!("Timed out flushing torrents to {}", backend.name());
+                false
+            }
+        }
+    };
+
+    let snapshot = async {
+        let path = match &config.snapshot.path {
+            Some(path) => path.clone(),
+            None => return true,
+        };
+
+        match timeout(limit, janitor::save_snapshot(state, path)).await {
+            Ok(Ok(peers)) => {
+                info!("Saved {} peers to snapshot", peers);
+                true
+            }
+            Ok(Err(e)) => {
+                error!("Could not save peer snapshot: {}", e);
+                false
+            }
+            Err(_) => {
+                error!("Timed out saving peer snapshot");
+                false
+            }
+        }
+    };
+
+    let (flushed, saved) = join(flush, snapshot).await;
+    flushed && saved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+
+    struct Failing;
+
+    impl TorrentBackend for Failing {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn load_torrents(&self) -> Result<TorrentRecords, String> {
+            Err("unavailable".to_string())
+        }
+
+        fn load_changed_since(&self, _since: u64) -> Result<Vec<Torrent>, String> {
+            Err("unavailable".to_string())
+        }
+
+        fn flush_torrents(&self, _torrents: &[Torrent]) -> Result<(), String> {
+            Err("unavailable".to_string())
+        }
+
+        fn health_check(&self) -> Result<(), String> {
+            Err("unavailable".to_string())
+        }
+    }
+
+    struct Working {
+        flushed: parking_lot::Mutex<usize>,
+    }
+
+    impl TorrentBackend for Working {
+        fn name(&self) -> &'static str {
+            "working"
+        }
+
+        fn load_torrents(&self) -> Result<TorrentRecords, String> {
+            Ok(TorrentRecords::new())
+        }
+
+        fn load_changed_since(&self, _since: u64) -> Result<Vec<Torrent>, String> {
+            Ok(Vec::new())
+        }
+
+        fn flush_torrents(&self, torrents: &[Torrent]) -> Result<(), String> {
+            *self.flushed.lock() += torrents.len();
+            Ok(())
+        }
+
+        fn health_check(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn shutdown_persistence_result() {
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let mut records = TorrentRecords::new();
+        records.insert(
+            info_hash.clone(),
+            Torrent::new(info_hash.clone(), 0, 0, 0, 0),
+        );
+        let state = State::new(Config::default(), TorrentStore::new(records));
+        state.torrent_store.new_leech(info_hash).await;
+
+        assert!(!persist(&state, Arc::new(Failing)).await);
+
+        // A flush that is still running is waited for instead of failing
+        let running = state.torrent_store.begin_flush().unwrap();
+        actix_rt::spawn(async move {
+            delay_for(Duration::from_millis(200)).await;
+            drop(running);
+        });
+        let working = Arc::new(Working {
+            flushed: parking_lot::Mutex::new(0),
+        });
+        assert!(persist(&state, working.clone()).await);
+        assert!(!state.torrent_store.is_flushing());
+        assert_eq!(*working.flushed.lock(), 1);
+
+        assert!(persist(&state, Arc::new(crate::storage::memory::Memory)).await);
+    }
+}
@@ -2,24 +2,39 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 
 use crate::config::Config;
-use crate::statistics::GlobalStatistics;
+use crate::network::ban::BanList;
+use crate::statistics::{GlobalStatistics, StorageStatus};
 use crate::storage::{PeerStore, TorrentStore};
 
 #[derive(Clone)]
 pub struct State {
-    pub config: Config,
+    pub bans: Arc<BanList>,
+    config: Arc<parking_lot::RwLock<Arc<Config>>>,
     pub peer_store: PeerStore,
     pub stats: Arc<RwLock<GlobalStatistics>>,
+    pub storage: Arc<parking_lot::RwLock<StorageStatus>>,
     pub torrent_store: TorrentStore,
 }
 
 impl State {
     pub fn new(config: Config, torrent_store: TorrentStore) -> State {
         State {
-            config,
+            bans: Arc::new(BanList::new()),
+            config: Arc::new(parking_lot::RwLock::new(Arc::new(config))),
             peer_store: PeerStore::new(),
             stats: Arc::new(RwLock::new(GlobalStatistics::new())),
+            storage: Arc::new(parking_lot::RwLock::new(StorageStatus::default())),
             torrent_store,
         }
     }
+
+    // The configuration can be swapped out at any time, so a request
+    // should hold on to one snapshot rather than calling this repeatedly
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().clone()
+    }
+
+    pub fn replace_config(&self, config: Config) {
+        *self.config.write() = Arc::new(config);
+    }
 }
@@ -10,9 +10,17 @@ pub struct GlobalStatistics {
     pub announce_requests: u32,
     pub succ_announces: u32,
     pub scrapes: u32,
+    pub rejected_ip_overrides: u32,
+    window_start: Instant,
+    window_requests: u32,
+    request_rate: f64,
 }
 
+// Throughput is sampled over windows of this many seconds
+const RATE_WINDOW_SECS: f64 = 10.0;
+
 impl GlobalStatistics {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> GlobalStatistics {
         GlobalStatistics {
             start_time: Instant::now(),
@@ -21,6 +29,10 @@ impl GlobalStatistics {
             announce_requests: 0,
             succ_announces: 0,
             scrapes: 0,
+            rejected_ip_overrides: 0,
+            window_start: Instant::now(),
+            window_requests: 0,
+            request_rate: 0.0,
         }
     }
 
@@ -31,10 +43,12 @@ impl GlobalStatistics {
     pub fn succ_announce(&mut self) {
         self.announce_requests += 1;
         self.succ_announces += 1;
+        self.record_request();
     }
 
     pub fn fail_announce(&mut self) {
         self.announce_requests += 1;
+        self.record_request();
     }
 
     pub fn num_fails(&self) -> u32 {
@@ -43,6 +57,36 @@ impl GlobalStatistics {
 
     pub fn incr_scrapes(&mut self) {
         self.scrapes += 1;
+        self.record_request();
+    }
+
+    pub fn reject_ip_override(&mut self) {
+        self.rejected_ip_overrides += 1;
+    }
+
+    // Once a window has elapsed, its request count becomes
+    // the current rate and a fresh window is started
+    fn record_request(&mut self) {
+        self.window_requests += 1;
+
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if elapsed >= RATE_WINDOW_SECS {
+            self.request_rate = f64::from(self.window_requests) / elapsed;
+            self.window_requests = 0;
+            self.window_start = Instant::now();
+        }
+    }
+
+    // Requests per second over the last completed window. Windows are only
+    // closed by requests, so a window that has run out is counted as it
+    // stands; without requests, the rate then falls off over time.
+    pub fn request_rate(&self) -> f64 {
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if elapsed >= RATE_WINDOW_SECS {
+            return f64::from(self.window_requests) / elapsed;
+        }
+
+        self.request_rate
     }
 
     pub fn add_seed(&mut self) {
@@ -66,12 +110,55 @@ impl GlobalStatistics {
         self.total_seeders += 1;
     }
 
+    pub fn restored_peers(&mut self, seeders: u32, leechers: u32) {
+        self.total_seeders += seeders;
+        self.total_leechers += leechers;
+    }
+
     pub fn cleared_peers(&mut self, seeders_cleared: u32, leechers_cleared: u32) {
         self.total_seeders = self.total_seeders.saturating_sub(seeders_cleared);
         self.total_leechers = self.total_leechers.saturating_sub(leechers_cleared);
     }
 }
 
+// Kept up to date by every flush and fetch, so that monitoring can
+// alert on a backend that keeps failing. A degraded tracker started
+// without its backend and is waiting for it to come back.
+#[derive(Clone, Serialize, Debug, Default)]
+pub struct StorageStatus {
+    pub backend: String,
+    pub healthy: bool,
+    pub degraded: bool,
+    pub consecutive_failures: u32,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    pub dirty_torrents: usize,
+}
+
+impl StorageStatus {
+    pub fn new(backend: &str, degraded: bool) -> StorageStatus {
+        StorageStatus {
+            backend: backend.to_string(),
+            healthy: !degraded,
+            degraded,
+            ..StorageStatus::default()
+        }
+    }
+
+    pub fn succeeded(&mut self, now: u64) {
+        self.healthy = true;
+        self.degraded = false;
+        self.consecutive_failures = 0;
+        self.last_success = Some(now);
+    }
+
+    pub fn failed(&mut self, error: &str) {
+        self.healthy = false;
+        self.consecutive_failures += 1;
+        self.last_error = Some(error.to_string());
+    }
+}
+
 // This is a separate struct that will be returned through
 // the statistics handler. It looks mostly the same as
 // GlobalStatistics but the structs will soon diverge.
@@ -83,10 +170,13 @@ pub struct ReturnedStatistics {
     pub announce_requests: u32,
     pub succ_announces: u32,
     pub scrapes: u32,
+    pub rejected_ip_overrides: u32,
+    pub request_rate: f64,
+    pub storage: StorageStatus,
 }
 
 impl ReturnedStatistics {
-    pub fn new(stats: &GlobalStatistics) -> ReturnedStatistics {
+    pub fn new(stats: &GlobalStatistics, storage: StorageStatus) -> ReturnedStatistics {
         ReturnedStatistics {
             uptime: stats.uptime(),
             total_seeders: stats.total_seeders,
@@ -94,6 +184,34 @@ impl ReturnedStatistics {
             announce_requests: stats.announce_requests,
             succ_announces: stats.succ_announces,
             scrapes: stats.scrapes,
+            rejected_ip_overrides: stats.rejected_ip_overrides,
+            request_rate: stats.request_rate(),
+            storage,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn request_rate_decays_when_idle() {
+        let mut stats = GlobalStatistics::new();
+        for _ in 0..100 {
+            stats.succ_announce();
+        }
+        stats.window_start = Instant::now() - Duration::from_secs(10);
+        stats.succ_announce();
+        assert!(stats.request_rate() >= 9.0);
+
+        // No requests for a minute
+        stats.window_start = Instant::now() - Duration::from_secs(60);
+        assert_eq!(stats.request_rate(), 0.0);
+
+        stats.window_requests = 30;
+        assert!((stats.request_rate() - 0.5).abs() < 0.01);
+    }
+}
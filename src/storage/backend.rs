@@ -0,0 +1,304 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_rt::time::delay_for;
+use parking_lot::Mutex;
+
+use crate::config;
+use crate::network::ban::Ban;
+use crate::storage::{
+    memory::Memory, migrations, mysql::MySql, postgres::Postgres, sqlite::Sqlite, Torrent,
+    TorrentRecords,
+};
+
+// Everything the tracker needs from persistent storage. Implementations
+// are blocking, so callers outside of startup should go through web::block.
+pub trait TorrentBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Backends that don't persist anything don't need to be flushed or polled
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    fn load_torrents(&self) -> Result<TorrentRecords, String>;
+
+    // Torrents that were added or changed at or after the given unix time
+    fn load_changed_since(&self, since: u64) -> Result<Vec<Torrent>, String>;
+
+    // Every torrent that exists, so that deleted ones can be told apart
+    fn load_info_hashes(&self) -> Result<Vec<String>, String> {
+        Ok(self.load_torrents()?.into_iter().map(|(k, _)| k).collect())
+    }
+
+    // Only torrents that still exist are written, so that a
+    // flush can't bring back one that has been deleted
+    fn flush_torrents(&self, torrents: &[Torrent]) -> Result<(), String>;
+
+    // Backends without a place to keep bans simply have none
+    fn load_bans(&self) -> Result<Vec<Ban>, String> {
+        Ok(Vec::new())
+    }
+
+    fn health_check(&self) -> Result<(), String>;
+
+    // Numbered schema changes, see the migrations module. Backends
+    // without a schema have none and are always up to date.
+    fn migrations(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    // The last migration that was applied, or 0 for an empty database
+    fn schema_version(&self) -> Result<u32, String> {
+        Ok(0)
+    }
+
+    // Whether the database has no tables at all, so that its
+    // schema can be created without running 'tyto migrate'
+    fn is_blank(&self) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    // Runs a migration and records it in schema_version
+    fn apply_migration(&self, _version: u32, _migration: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub fn open(storage: &config::Storage) -> Result<Arc<dyn TorrentBackend>, String> {
+    match known(&storage.backend)? {
+        "memory" => Ok(Arc::new(Memory)),
+        "mysql" => Ok(Arc::new(MySql::connect(storage)?)),
+        "postgres" => Ok(Arc::new(Postgres::connect(storage)?)),
+        _ => Ok(Arc::new(Sqlite::open(storage)?)),
+    }
+}
+
+fn known(name: &str) -> Result<&'static str, String> {
+    ["memory", "mysql", "postgres", "sqlite"]
+        .iter()
+        .find(|known| **known == name)
+        .copied()
+        .ok_or_else(|| format!("Unknown storage backend '{}'", name))
+}
+
+// Waits start at 'retry_backoff' seconds and double with every
+// attempt, up to 'max_backoff' seconds
+pub fn backoff(storage: &config::Storage, attempt: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+    let secs = storage.retry_backoff.saturating_mul(factor);
+    Duration::new(secs.min(storage.max_backoff), 0)
+}
+
+// Opens the backend, checks its schema and loads every torrent, trying
+// again with backoff until 'connect_retries' attempts have failed
+pub async fn connect(
+    storage: &config::Storage,
+) -> Result<(Arc<dyn TorrentBackend>, TorrentRecords), String> {
+    known(&storage.backend)?;
+
+    let mut attempt = 0;
+    loop {
+        let loaded = open(storage).and_then(|backend| {
+            backend.health_check()?;
+            let version = backend.schema_version()?;
+            let blank = version == 0 && backend.is_blank()?;
+            Ok((backend, version, blank))
+        });
+
+        // Waiting won't fix a schema that doesn't match
+        let loaded = match loaded {
+            Ok((backend, version, blank)) => {
+                if blank {
+                    migrations::create(backend.as_ref())?;
+                } else {
+                    migrations::compatible(backend.as_ref(), version)?;
+                }
+                backend.load_torrents().map(|torrents| (backend, torrents))
+            }
+            Err(e) => Err(e),
+        };
+
+        match loaded {
+            Ok(loaded) => return Ok(loaded),
+            Err(e) if attempt < storage.connect_retries => {
+                let delay = backoff(storage, attempt);
+                warn!(
+                    "Could not load torrents from {}: {}; trying again in {} secs",
+                    storage.backend,
+                    e,
+                    delay.as_secs()
+                );
+                delay_for(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Stands in for a backend that couldn't be reached at startup,
+// connecting on first use once it can be reached again
+pub struct Deferred {
+    name: &'static str,
+    storage: config::Storage,
+    backend: Mutex<Option<Arc<dyn TorrentBackend>>>,
+}
+
+impl Deferred {
+    pub fn new(storage: &config::Storage) -> Result<Deferred, String> {
+        Ok(Deferred {
+            name: known(&storage.backend)?,
+            storage: storage.clone(),
+            backend: Mutex::new(None),
+        })
+    }
+
+    fn backend(&self) -> Result<Arc<dyn TorrentBackend>, String> {
+        let mut backend = self.backend.lock();
+        if let Some(backend) = &*backend {
+            return Ok(backend.clone());
+        }
+
+        let opened = open(&self.storage)?;
+        migrations::prepare(opened.as_ref())?;
+        info!("Connected to {} storage", self.name);
+        *backend = Some(opened.clone());
+
+        Ok(opened)
+    }
+}
+
+impl TorrentBackend for Deferred {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn load_torrents(&self) -> Result<TorrentRecords, String> {
+        self.backend()?.load_torrents()
+    }
+
+    fn load_changed_since(&self, since: u64) -> Result<Vec<Torrent>, String> {
+        self.backend()?.load_changed_since(since)
+    }
+
+    fn load_info_hashes(&self) -> Result<Vec<String>, String> {
+        self.backend()?.load_info_hashes()
+    }
+
+    fn flush_torrents(&self, torrents: &[Torrent]) -> Result<(), String> {
+        self.backend()?.flush_torrents(torrents)
+    }
+
+    fn load_bans(&self) -> Result<Vec<Ban>, String> {
+        self.backend()?.load_bans()
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        self.backend()?.health_check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_memory() {
+        let backend = open(&config::Storage::default()).unwrap();
+        assert_eq!(backend.name(), "memory");
+        assert!(!backend.persistent());
+        assert!(backend.health_check().is_ok());
+        assert!(backend.load_torrents().unwrap().is_empty());
+
+        let torrent = Torrent::new("A1B2C3D4E5F6G7H8I9J0".to_string(), 1, 2, 3, 4);
+        assert!(backend.flush_torrents(&[torrent]).is_ok());
+        assert!(backend.load_changed_since(0).unwrap().is_empty());
+        assert!(backend.load_info_hashes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backend_retry_backoff() {
+        let storage = config::Storage {
+            retry_backoff: 2,
+            max_backoff: 20,
+            ..config::Storage::default()
+        };
+        let delays: Vec<u64> = (0..5).map(|i| backoff(&storage, i).as_secs()).collect();
+        assert_eq!(delays, vec![2, 4, 8, 16, 20]);
+        assert_eq!(backoff(&storage, 100).as_secs(), 20);
+    }
+
+    #[test]
+    fn backend_deferred_connects_on_use() {
+        let path = std::env::temp_dir().join(format!("tyto-test-{}-deferred", std::process::id()));
+        let storage = config::Storage {
+            backend: "sqlite".to_string(),
+            path: path.join("tyto.db").to_str().unwrap().to_string(),
+            ..config::Storage::default()
+        };
+
+        // The directory for the database doesn't exist yet
+        let deferred = Deferred::new(&storage).unwrap();
+        assert_eq!(deferred.name(), "sqlite");
+        assert!(deferred.health_check().is_err());
+
+        // Then the database has tables, but no schema version
+        std::fs::create_dir(&path).unwrap();
+        rusqlite::Connection::open(&storage.path)
+            .unwrap()
+            .execute_batch("CREATE TABLE torrents (info_hash TEXT)")
+            .unwrap();
+        assert!(deferred.health_check().is_err());
+
+        std::fs::remove_file(&storage.path).unwrap();
+        assert!(deferred.health_check().is_ok());
+        assert!(deferred.load_torrents().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn backend_creates_schema_on_first_start() {
+        let path = std::env::temp_dir().join(format!("tyto-test-{}-blank.db", std::process::id()));
+        let storage = config::Storage {
+            backend: "sqlite".to_string(),
+            path: path.to_str().unwrap().to_string(),
+            connect_retries: 0,
+            ..config::Storage::default()
+        };
+
+        let (backend, torrents) = connect(&storage).await.unwrap();
+        assert!(torrents.is_empty());
+        assert_eq!(
+            backend.schema_version().unwrap(),
+            migrations::latest(backend.as_ref())
+        );
+        drop(backend);
+
+        // A schema that exists but is out of date is still refused
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch("DELETE FROM schema_version WHERE version > 1")
+            .unwrap();
+        match connect(&storage).await {
+            Err(e) => assert!(e.contains("run 'tyto migrate'")),
+            Ok(_) => panic!("started against an old schema"),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn backend_unknown_rejected() {
+        let storage = config::Storage {
+            backend: "floppy".to_string(),
+            ..config::Storage::default()
+        };
+
+        match open(&storage) {
+            Err(e) => assert_eq!(e, "Unknown storage backend 'floppy'"),
+            Ok(_) => panic!("opened an unknown backend"),
+        }
+    }
+}
@@ -1,30 +1,49 @@
 use crate::bittorrent::Peer;
+use crate::config::Config;
+use crate::network::ban::{self, Ban};
+use crate::network::tls::CertificateResolver;
 use crate::state::State;
-use crate::storage;
+use crate::storage::backend::{self, TorrentBackend};
+use crate::storage::snapshot::Snapshot;
+use crate::storage::Busy;
 
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use actix::prelude::*;
+use actix_rt::time::{delay_for, timeout};
+use actix_web::error::BlockingError;
 use actix_web::web;
-use mysql::*;
 
 #[derive(Clone)]
 pub struct Janitor {
     reap_interval: Duration,
-    peer_timeout: Duration,
     flush_interval: Duration,
     state: web::Data<State>,
-    pool: Pool,
+    backend: Arc<dyn TorrentBackend>,
+    certificates: Option<Arc<CertificateResolver>>,
+    last_reconcile: Arc<AtomicU64>,
+    reconciling: Arc<AtomicBool>,
+    retrying_flush: Arc<AtomicBool>,
 }
 
 impl Janitor {
-    pub fn new(state: web::Data<State>, pool: Pool) -> Janitor {
+    pub fn new(
+        state: web::Data<State>,
+        backend: Arc<dyn TorrentBackend>,
+        certificates: Option<Arc<CertificateResolver>>,
+        reconcile_since: u64,
+    ) -> Janitor {
         Janitor {
-            reap_interval: Duration::new(state.config.bt.reap_interval, 0),
-            peer_timeout: Duration::new(state.config.bt.peer_timeout, 0),
-            flush_interval: Duration::new(state.config.bt.flush_interval, 0),
+            reap_interval: Duration::new(state.config().bt.reap_interval, 0),
+            flush_interval: Duration::new(state.config().bt.flush_interval, 0),
             state,
-            pool,
+            backend,
+            certificates,
+            last_reconcile: Arc::new(AtomicU64::new(reconcile_since)),
+            reconciling: Arc::new(AtomicBool::new(false)),
+            retrying_flush: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -34,6 +53,9 @@ impl Janitor {
         ctx.spawn(actix::fut::wrap_future(async move {
             info!("Clearing away stale peers...");
 
+            // The timeout can be changed by a configuration reload
+            let peer_timeout = Duration::new(self2.state.config().bt.peer_timeout, 0);
+
             let mut seeds_cleared = 0;
             let mut leeches_cleared = 0;
 
@@ -60,12 +82,12 @@ impl Janitor {
                     let leeches_1 = swarm.leechers.len();
 
                     swarm.seeders.retain(|peer| match peer {
-                        Peer::V4(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                        Peer::V6(p) => p.last_announced.elapsed() < self2.peer_timeout,
+                        Peer::V4(p) => p.last_announced.elapsed() < peer_timeout,
+                        Peer::V6(p) => p.last_announced.elapsed() < peer_timeout,
                     });
                     swarm.leechers.retain(|peer| match peer {
-                        Peer::V4(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                        Peer::V6(p) => p.last_announced.elapsed() < self2.peer_timeout,
+                        Peer::V4(p) => p.last_announced.elapsed() < peer_timeout,
+                        Peer::V6(p) => p.last_announced.elapsed() < peer_timeout,
                     });
 
                     seeds_cleared += seeds_1 - swarm.seeders.len();
@@ -93,47 +115,171 @@ impl Janitor {
     fn flush(&mut self, ctx: &mut Context<Self>) {
         let self2 = self.clone();
         ctx.spawn(actix::fut::wrap_future(async move {
-            info!("Flushing torrents to database...");
+            // Changes stay in memory while a failed flush is being retried,
+            // so there's no need for another one to start in the meantime
+            let _retrying = match Busy::try_start(&self2.retrying_flush) {
+                Some(retrying) => retrying,
+                None => return,
+            };
 
-            let torrents: Vec<storage::Torrent> = self2
-                .state
-                .torrent_store
-                .torrents
-                .read()
-                .await
-                .iter()
-                .map(|(_, torrent)| torrent.clone())
-                .collect();
+            let mut attempt = 0;
+            loop {
+                info!("Flushing torrents to {}...", self2.backend.name());
+
+                match flush_torrents(&self2.state, self2.backend.clone()).await {
+                    Ok(num_torrents) => {
+                        info!("Flushed {} torrents.", num_torrents);
+                        return;
+                    }
+                    Err(e) => {
+                        let delay = backend::backoff(&self2.state.config().storage, attempt);
+                        error!(
+                            "Could not flush torrents: {}; trying again in {} secs",
+                            e,
+                            delay.as_secs()
+                        );
+                        delay_for(delay).await;
+                        attempt += 1;
+                    }
+                }
+            }
+        }));
+    }
+
+    // Brings in torrents that were added or changed in the database since
+    // the last pass, and drops those that were deleted along with their swarms
+    fn reconcile_torrents(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            // A running flush holds changes that the database hasn't seen yet
+            let generation = self2.state.torrent_store.flush_generation();
+            if self2.state.torrent_store.is_flushing() {
+                warn!("Skipping reconciliation, torrents are being flushed.");
+                return;
+            }
+            let busy = match Busy::try_start(&self2.reconciling) {
+                Some(busy) => busy,
+                None => {
+                    warn!("Skipping reconciliation, the previous one is still running.");
+                    return;
+                }
+            };
+
+            info!("Reconciling torrents with {}...", self2.backend.name());
+
+            // Only torrents changed since the last successful pass are loaded;
+            // the window overlaps, but unchanged torrents are left alone.
+            // Deletions can only be found by comparing every info hash.
+            let started = ban::now();
+            let since = self2.last_reconcile.load(Ordering::Relaxed);
+            let backend = self2.backend.clone();
+            let timeout = self2.state.config().storage.timeout;
+            match blocking(timeout, move || {
+                let _busy = busy;
+                let changed = backend.load_changed_since(since)?;
+                let existing = backend.load_info_hashes()?;
+                Ok((changed, existing.into_iter().collect()))
+            })
+            .await
+            {
+                Ok((changed, existing)) => {
+                    self2.state.storage.write().succeeded(ban::now());
+                    let reconciled = self2
+                        .state
+                        .torrent_store
+                        .reconcile(changed, &existing, generation)
+                        .await;
 
-            let num_torrents = torrents.len();
+                    // Updates put off by a flush are loaded again next time
+                    if reconciled.deferred {
+                        warn!("Torrents were flushed while reconciling, updates are put off.");
+                    } else {
+                        self2.last_reconcile.store(started, Ordering::Relaxed);
+                    }
+                    let (seeders, leechers) = self2
+                        .state
+                        .peer_store
+                        .remove_swarms(&reconciled.removed)
+                        .await;
+                    self2
+                        .state
+                        .stats
+                        .write()
+                        .await
+                        .cleared_peers(seeders, leechers);
 
-            let _result = storage::mysql::flush_torrents(self2.pool, torrents);
+                    info!(
+                        "Added {}, updated {} and removed {} torrents from {}.",
+                        reconciled.added,
+                        reconciled.updated,
+                        reconciled.removed.len(),
+                        self2.backend.name()
+                    );
+                }
+                Err(e) => {
+                    self2.state.storage.write().failed(&e);
+                    error!("Could not reconcile torrents: {}", e);
+                }
+            }
+        }));
+    }
 
-            info!("Flushed {} torrents.", num_torrents);
+    fn save_snapshot(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            if let Some(path) = self2.state.config().snapshot.path.clone() {
+                match save_snapshot(&self2.state, path).await {
+                    Ok(peers) => info!("Saved {} peers to snapshot.", peers),
+                    Err(e) => error!("Could not save peer snapshot: {}", e),
+                }
+            }
         }));
     }
 
-    fn fetch_new_torrents(&mut self, ctx: &mut Context<Self>) {
+    // Bans are always reloaded so that they can be
+    // turned on and off by a configuration reload
+    fn reload_bans(&mut self, ctx: &mut Context<Self>) {
         let self2 = self.clone();
         ctx.spawn(actix::fut::wrap_future(async move {
-            info!("Fetching new torrents from database...");
-
-            match storage::mysql::get_torrents(self2.pool) {
-                Ok(db_torrents) => {
-                    let mut diff = 0;
-                    let mut torrent_store = self2.state.torrent_store.torrents.write().await;
-                    for (info_hash, torrent) in db_torrents.iter() {
-                        if !torrent_store.contains_key(&info_hash[..].to_string()) {
-                            torrent_store.insert(info_hash.clone(), torrent.clone());
-                            diff += 1;
-                        }
+            let config = self2.state.config();
+            match load_bans(config.clone(), self2.backend.clone()).await {
+                Ok(bans) => {
+                    let total = self2.state.bans.replace(bans);
+                    if config.bans.enabled() {
+                        info!("Reloaded {} bans.", total);
                     }
-                    info!("Added new {} torrents from database.", diff);
                 }
-                _ => error!("Could not fetch new torrents from database!"),
+                Err(e) => error!("Could not reload bans: {}", e),
             }
         }));
     }
+
+    fn reload_certificates(&mut self, _ctx: &mut Context<Self>) {
+        if let Some(certificates) = &self.certificates {
+            match certificates.reload() {
+                Ok(true) => info!("Reloaded TLS certificate."),
+                Ok(false) => {}
+                Err(e) => error!("Could not reload TLS certificate: {}", e),
+            }
+        }
+    }
+}
+
+// Sent on shutdown, so that nothing the janitor starts can get in
+// the way of the last flush. Whatever it has running is dropped.
+pub struct Stop;
+
+impl Message for Stop {
+    type Result = ();
+}
+
+impl Handler<Stop> for Janitor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Stop, ctx: &mut Context<Self>) {
+        info!("Janitor is off duty.");
+        ctx.stop();
+    }
 }
 
 impl Actor for Janitor {
@@ -146,15 +292,279 @@ impl Actor for Janitor {
         // any peers that have not announced in a defined time
         ctx.run_interval(self.reap_interval, Self::clear_peers);
 
-        // This will flush all torrent data to the database
-        // to ensure that stats are up-to-date
-        ctx.run_interval(self.flush_interval, Self::flush);
+        // Without persistence there is nothing to flush to or reconcile with
+        if self.backend.persistent() {
+            // This will flush all torrent data to the database
+            // to ensure that stats are up-to-date
+            ctx.run_interval(self.flush_interval, Self::flush);
+
+            // This will pull new, changed and deleted torrents
+            // from the database into the torrent store
+            ctx.run_interval(
+                Duration::new(self.state.config().bt.announce_rate, 0),
+                Self::reconcile_torrents,
+            );
+        }
+
+        // This will save all swarms so that they survive a restart
+        let snapshot = &self.state.config().snapshot;
+        if snapshot.path.is_some() {
+            ctx.run_interval(Duration::new(snapshot.interval, 0), Self::save_snapshot);
+        }
 
-        // This will pull any new torrents from the database
-        // and add them to the torrent store
+        // This will pick up changes to the ban list and drop expired bans
         ctx.run_interval(
-            Duration::new(self.state.config.bt.announce_rate, 0),
-            Self::fetch_new_torrents,
+            Duration::new(self.state.config().bans.reload_interval, 0),
+            Self::reload_bans,
         );
+
+        // This will pick up renewed certificates for the TLS listener
+        if self.certificates.is_some() {
+            ctx.run_interval(
+                Duration::new(self.state.config().network.tls_reload_interval, 0),
+                Self::reload_certificates,
+            );
+        }
+    }
+}
+
+// The error of a blocking call is otherwise only shown in its debug form
+fn blocking_error(e: BlockingError<String>) -> String {
+    match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => "blocking call was canceled".to_string(),
+    }
+}
+
+// Storage is only ever used from here, on the thread pool, so that a slow
+// backend can't hold up requests. Waiting gives up after 'secs' seconds,
+// though the call itself keeps going in the background.
+pub async fn blocking<F, T>(secs: u64, f: F) -> Result<T, String>
+where
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    match timeout(Duration::new(secs, 0), web::block(f)).await {
+        Ok(result) => result.map_err(blocking_error),
+        Err(_) => Err(format!("timed out after {} secs", secs)),
+    }
+}
+
+// Only torrents that changed since the last flush are written, one
+// transaction per batch. Batches that couldn't be written, and those
+// after them, stay dirty for the next flush. Returns the number of
+// torrents that were flushed.
+pub async fn flush_torrents(
+    state: &State,
+    backend: Arc<dyn TorrentBackend>,
+) -> Result<usize, String> {
+    let busy = state
+        .torrent_store
+        .begin_flush()
+        .ok_or_else(|| "the previous flush is still running".to_string())?;
+
+    let config = state.config();
+    let batch_size = config.bt.flush_batch_size.max(1);
+    let torrents = state.torrent_store.take_dirty().await;
+    let torrent_store = state.torrent_store.clone();
+
+    let result = blocking(config.storage.timeout, move || {
+        let _busy = busy;
+        let mut flushed = 0;
+
+        for (i, batch) in torrents.chunks(batch_size).enumerate() {
+            if let Err(e) = backend.flush_torrents(batch) {
+                let unflushed = &torrents[i * batch_size..];
+                torrent_store.mark_dirty(unflushed.iter().map(|t| &t.info_hash));
+                return Err(format!(
+                    "{} (flushed {}, {} left for the next flush)",
+                    e,
+                    flushed,
+                    unflushed.len()
+                ));
+            }
+            flushed += batch.len();
+        }
+
+        Ok(flushed)
+    })
+    .await;
+
+    match &result {
+        Ok(_) => state.storage.write().succeeded(ban::now()),
+        Err(e) => state.storage.write().failed(e),
+    }
+
+    result
+}
+
+// Writing the file happens off of the async threads; returns the number of peers saved
+pub async fn save_snapshot(state: &State, path: String) -> Result<usize, String> {
+    let snapshot = Snapshot::capture(&state.peer_store, &state.torrent_store).await;
+    let peers = snapshot.peers();
+    let timeout = state.config().storage.timeout;
+    blocking(timeout, move || snapshot.write(&path)).await?;
+
+    Ok(peers)
+}
+
+pub async fn load_bans(
+    config: Arc<Config>,
+    backend: Arc<dyn TorrentBackend>,
+) -> Result<Vec<Ban>, String> {
+    let timeout = config.storage.timeout;
+    blocking(timeout, move || Ok(collect_bans(&config, backend.as_ref()))).await
+}
+
+// Gathers bans from every configured source. A source that can't
+// be read is skipped so that the others still take effect.
+pub fn collect_bans(config: &Config, backend: &dyn TorrentBackend) -> Vec<Ban> {
+    let mut bans = Vec::new();
+
+    if let Some(file) = &config.bans.file {
+        match ban::load_file(file) {
+            Ok(file_bans) => bans.extend(file_bans),
+            Err(e) => error!("Could not read ban file {}: {}", file, e),
+        }
+    }
+
+    if config.bans.database {
+        match backend.load_bans() {
+            Ok(db_bans) => bans.extend(db_bans),
+            Err(e) => error!("Could not fetch bans from {}: {}", backend.name(), e),
+        }
+    }
+
+    bans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+
+    // Accepts a number of batches and fails after that
+    struct Flaky {
+        accepted: parking_lot::Mutex<Vec<usize>>,
+        limit: usize,
+    }
+
+    impl TorrentBackend for Flaky {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn load_torrents(&self) -> Result<TorrentRecords, String> {
+            Ok(TorrentRecords::new())
+        }
+
+        fn load_changed_since(&self, _since: u64) -> Result<Vec<Torrent>, String> {
+            Ok(Vec::new())
+        }
+
+        fn flush_torrents(&self, torrents: &[Torrent]) -> Result<(), String> {
+            let mut accepted = self.accepted.lock();
+            if accepted.len() == self.limit {
+                return Err("unavailable".to_string());
+            }
+            accepted.push(torrents.len());
+            Ok(())
+        }
+
+        fn health_check(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn flush_keeps_failed_batches_dirty() {
+        let mut records = TorrentRecords::new();
+        for i in 0..5 {
+            let info_hash = format!("{:020}", i);
+            records.insert(info_hash.clone(), Torrent::new(info_hash, 0, 0, 0, 0));
+        }
+        let info_hashes: Vec<String> = records.keys().cloned().collect();
+
+        let mut config = Config::default();
+        config.bt.flush_batch_size = 2;
+        let state = State::new(config, TorrentStore::new(records));
+        for info_hash in info_hashes {
+            state.torrent_store.new_leech(info_hash).await;
+        }
+
+        let backend = Arc::new(Flaky {
+            accepted: parking_lot::Mutex::new(Vec::new()),
+            limit: 1,
+        });
+        assert!(flush_torrents(&state, backend.clone()).await.is_err());
+        assert_eq!(*backend.accepted.lock(), vec![2]);
+        assert_eq!(state.torrent_store.dirty_count(), 3);
+
+        let backend = Arc::new(Flaky {
+            accepted: parking_lot::Mutex::new(Vec::new()),
+            limit: 5,
+        });
+        assert_eq!(flush_torrents(&state, backend.clone()).await, Ok(3));
+        assert_eq!(*backend.accepted.lock(), vec![2, 1]);
+        assert_eq!(state.torrent_store.dirty_count(), 0);
+    }
+
+    // Flushes once it's told to
+    struct Stuck(parking_lot::Mutex<std::sync::mpsc::Receiver<()>>);
+
+    impl TorrentBackend for Stuck {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        fn load_torrents(&self) -> Result<TorrentRecords, String> {
+            Ok(TorrentRecords::new())
+        }
+
+        fn load_changed_since(&self, _since: u64) -> Result<Vec<Torrent>, String> {
+            Ok(Vec::new())
+        }
+
+        fn flush_torrents(&self, _torrents: &[Torrent]) -> Result<(), String> {
+            self.0.lock().recv().map_err(|e| e.to_string())
+        }
+
+        fn health_check(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn flush_times_out_without_overlapping() {
+        let mut config = Config::default();
+        config.storage.timeout = 1;
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let mut records = TorrentRecords::new();
+        records.insert(
+            info_hash.clone(),
+            Torrent::new(info_hash.clone(), 0, 0, 0, 0),
+        );
+        let state = State::new(config, TorrentStore::new(records));
+        state.torrent_store.new_leech(info_hash).await;
+
+        let (release, gate) = std::sync::mpsc::channel();
+        let backend = Arc::new(Stuck(parking_lot::Mutex::new(gate)));
+
+        let result = flush_torrents(&state, backend.clone()).await;
+        assert_eq!(result, Err("timed out after 1 secs".to_string()));
+        assert!(state.torrent_store.is_flushing());
+
+        let result = flush_torrents(&state, backend.clone()).await;
+        assert_eq!(
+            result,
+            Err("the previous flush is still running".to_string())
+        );
+
+        release.send(()).unwrap();
+        while state.torrent_store.is_flushing() {
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(flush_torrents(&state, backend).await, Ok(0));
     }
 }
@@ -0,0 +1,33 @@
+use crate::storage::backend::TorrentBackend;
+use crate::storage::{Torrent, TorrentRecords};
+
+// Keeps nothing beyond what's already held by the torrent store, so
+// everything is lost on restart. Useful for development and testing,
+// or for an open tracker that has no need for history.
+pub struct Memory;
+
+impl TorrentBackend for Memory {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn persistent(&self) -> bool {
+        false
+    }
+
+    fn load_torrents(&self) -> Result<TorrentRecords, String> {
+        Ok(TorrentRecords::new())
+    }
+
+    fn load_changed_since(&self, _since: u64) -> Result<Vec<Torrent>, String> {
+        Ok(Vec::new())
+    }
+
+    fn flush_torrents(&self, _torrents: &[Torrent]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
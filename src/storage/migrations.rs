@@ -0,0 +1,176 @@
+// Every SQL backend carries its own numbered migrations, the first one
+// being version 1. The schema_version table records which have been
+// applied. A database without any tables gets its schema on first
+// start; otherwise migrations only run through 'tyto migrate', and the
+// tracker refuses to start against a schema it wasn't built for.
+
+use crate::config;
+use crate::storage::backend::{self, TorrentBackend};
+
+pub fn latest(backend: &dyn TorrentBackend) -> u32 {
+    backend.migrations().len() as u32
+}
+
+// Applies the migrations that are missing, in order. Returns the
+// versions the schema was at before and after.
+pub fn migrate(backend: &dyn TorrentBackend) -> Result<(u32, u32), String> {
+    let current = backend.schema_version()?;
+    let latest = latest(backend);
+    if current > latest {
+        return Err(newer(backend, current));
+    }
+
+    for (version, migration) in
+        (current + 1..=latest).zip(&backend.migrations()[current as usize..])
+    {
+        backend
+            .apply_migration(version, migration)
+            .map_err(|e| format!("Migration {} failed: {}", version, e))?;
+        info!("Applied {} migration {}", backend.name(), version);
+    }
+
+    Ok((current, latest))
+}
+
+// Runs for 'tyto migrate'
+pub fn run(storage: &config::Storage) -> Result<(), String> {
+    let backend = backend::open(storage)?;
+    if latest(backend.as_ref()) == 0 {
+        info!("The {} backend has no schema to migrate", backend.name());
+        return Ok(());
+    }
+
+    match migrate(backend.as_ref())? {
+        (from, to) if from == to => info!(
+            "The {} schema is up to date at version {}",
+            backend.name(),
+            to
+        ),
+        (from, to) => info!(
+            "Migrated the {} schema from version {} to {}",
+            backend.name(),
+            from,
+            to
+        ),
+    }
+
+    Ok(())
+}
+
+pub fn check(backend: &dyn TorrentBackend) -> Result<(), String> {
+    compatible(backend, backend.schema_version()?)
+}
+
+// Creates the schema of a blank database, or checks an existing one
+pub fn prepare(backend: &dyn TorrentBackend) -> Result<(), String> {
+    let version = backend.schema_version()?;
+    if version == 0 && backend.is_blank()? {
+        return create(backend);
+    }
+
+    compatible(backend, version)
+}
+
+pub fn create(backend: &dyn TorrentBackend) -> Result<(), String> {
+    let (_, to) = migrate(backend)?;
+    info!("Created the {} schema at version {}", backend.name(), to);
+    Ok(())
+}
+
+pub fn compatible(backend: &dyn TorrentBackend, version: u32) -> Result<(), String> {
+    let latest = latest(backend);
+    if version < latest {
+        return Err(format!(
+            "The {} schema is at version {}, but version {} is needed; run 'tyto migrate' first",
+            backend.name(),
+            version,
+            latest
+        ));
+    }
+    if version > latest {
+        return Err(newer(backend, version));
+    }
+
+    Ok(())
+}
+
+fn newer(backend: &dyn TorrentBackend, version: u32) -> String {
+    format!(
+        "The {} schema is at version {}, which is newer than the {} this version of Tyto knows",
+        backend.name(),
+        version,
+        latest(backend)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::{Torrent, TorrentRecords};
+
+    // Keeps applied versions in memory
+    struct Versioned {
+        applied: parking_lot::Mutex<Vec<u32>>,
+    }
+
+    impl TorrentBackend for Versioned {
+        fn name(&self) -> &'static str {
+            "versioned"
+        }
+
+        fn load_torrents(&self) -> Result<TorrentRecords, String> {
+            Ok(TorrentRecords::new())
+        }
+
+        fn load_changed_since(&self, _since: u64) -> Result<Vec<Torrent>, String> {
+            Ok(Vec::new())
+        }
+
+        fn flush_torrents(&self, _torrents: &[Torrent]) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn health_check(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn migrations(&self) -> &'static [&'static str] {
+            &["first", "second", "third"]
+        }
+
+        fn schema_version(&self) -> Result<u32, String> {
+            Ok(self.applied.lock().last().copied().unwrap_or(0))
+        }
+
+        fn apply_migration(&self, version: u32, migration: &str) -> Result<(), String> {
+            if migration == "third" {
+                return Err("syntax error".to_string());
+            }
+            self.applied.lock().push(version);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn migrations_apply_in_order() {
+        let backend = Versioned {
+            applied: parking_lot::Mutex::new(vec![1]),
+        };
+        assert!(check(&backend).unwrap_err().contains("run 'tyto migrate'"));
+
+        match migrate(&backend) {
+            Err(e) => assert_eq!(e, "Migration 3 failed: syntax error"),
+            Ok(_) => panic!("applied a failing migration"),
+        }
+        assert_eq!(*backend.applied.lock(), vec![1, 2]);
+
+        *backend.applied.lock() = vec![1, 2, 3];
+        assert!(check(&backend).is_ok());
+        assert_eq!(migrate(&backend).unwrap(), (3, 3));
+
+        backend.applied.lock().push(4);
+        assert!(check(&backend).unwrap_err().contains("newer"));
+        assert!(migrate(&backend).is_err());
+    }
+}
@@ -1,6 +1,14 @@
+pub mod backend;
 pub mod janitor;
+pub mod memory;
+pub mod migrations;
 pub mod mysql;
+pub mod postgres;
+pub mod snapshot;
+pub mod sqlite;
 
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 
 use hashbrown::{HashMap, HashSet};
@@ -9,7 +17,8 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::bittorrent::ScrapeFile;
-use crate::bittorrent::{CompactPeer, CompactPeerv4, CompactPeerv6, Peer};
+use crate::bittorrent::{CompactPeer, CompactPeerv4, CompactPeerv6, Crypto, Peer};
+use crate::obfuscation;
 
 #[derive(Debug, Clone)]
 struct PeerList(Vec<CompactPeer>);
@@ -32,7 +41,7 @@ impl PeerList {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Torrent {
     pub info_hash: String,
     pub complete: u32,   // Number of seeders
@@ -67,19 +76,135 @@ pub type TorrentRecords = HashMap<String, Torrent>;
 #[derive(Debug, Clone)]
 pub struct TorrentStore {
     pub torrents: Arc<RwLock<TorrentRecords>>,
+    obfuscated: Arc<RwLock<ObfuscatedHashes>>,
+    dirty: Arc<parking_lot::Mutex<HashSet<String>>>,
+    flushing: Arc<AtomicBool>,
+    flushes: Arc<AtomicU64>,
+    register_unknown: bool,
 }
 
+// Marks a blocking task as running for as long as it's held. The task
+// keeps the guard itself, so the mark stays even if whoever started it
+// stopped waiting, and the next run can't overlap with it.
+pub struct Busy(Arc<AtomicBool>);
+
+impl Busy {
+    pub fn try_start(flag: &Arc<AtomicBool>) -> Option<Busy> {
+        if flag.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        Some(Busy(flag.clone()))
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Reconciled {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: Vec<String>,
+    pub deferred: bool,
+}
+
+// BEP 08 announces only carry SHA-1(info_hash), so
+// a reverse lookup table is kept alongside the torrents
+type ObfuscatedHashes = HashMap<[u8; 20], String>;
+
 impl TorrentStore {
     pub fn new(torrent_records: TorrentRecords) -> TorrentStore {
+        let obfuscated = torrent_records
+            .keys()
+            .map(|info_hash| (obfuscation::sha1(info_hash.as_bytes()), info_hash.clone()))
+            .collect();
+
         TorrentStore {
             torrents: Arc::new(RwLock::new(torrent_records)),
+            obfuscated: Arc::new(RwLock::new(obfuscated)),
+            dirty: Arc::new(parking_lot::Mutex::new(HashSet::new())),
+            flushing: Arc::new(AtomicBool::new(false)),
+            flushes: Arc::new(AtomicU64::new(0)),
+            register_unknown: false,
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> TorrentStore {
-        TorrentStore {
-            torrents: Arc::new(RwLock::new(TorrentRecords::new())),
+        TorrentStore::new(TorrentRecords::new())
+    }
+
+    // Without a database to add torrents to, an open tracker
+    // takes on every torrent that's announced
+    pub fn register_unknown(mut self) -> TorrentStore {
+        self.register_unknown = true;
+        self
+    }
+
+    // Brings the store in line with the database. Torrents that changed
+    // there are added or updated, unless they have changes of their own
+    // waiting to be flushed, and those missing from 'existing' are
+    // removed. 'generation' is the flush generation from before the rows
+    // were loaded: if a flush has started since, the torrents it took are
+    // no longer marked dirty and the rows may predate it, so updates are
+    // put off until the next pass.
+    pub async fn reconcile(
+        &self,
+        changed: Vec<Torrent>,
+        existing: &HashSet<String>,
+        generation: u64,
+    ) -> Reconciled {
+        let mut records = self.torrents.write().await;
+        let mut obfuscated = self.obfuscated.write().await;
+        let mut dirty = self.dirty.lock();
+        let mut reconciled = Reconciled {
+            deferred: self.is_flushing() || self.flush_generation() != generation,
+            ..Reconciled::default()
+        };
+
+        for torrent in changed {
+            if !existing.contains(&torrent.info_hash) || dirty.contains(&torrent.info_hash) {
+                continue;
+            }
+            match records.get_mut(&torrent.info_hash) {
+                Some(known) => {
+                    if !reconciled.deferred && *known != torrent {
+                        *known = torrent;
+                        reconciled.updated += 1;
+                    }
+                }
+                None => {
+                    obfuscated.insert(
+                        obfuscation::sha1(torrent.info_hash.as_bytes()),
+                        torrent.info_hash.clone(),
+                    );
+                    records.insert(torrent.info_hash.clone(), torrent);
+                    reconciled.added += 1;
+                }
+            }
         }
+
+        reconciled.removed = records
+            .keys()
+            .filter(|info_hash| !existing.contains(*info_hash))
+            .cloned()
+            .collect();
+        for info_hash in &reconciled.removed {
+            records.remove(info_hash);
+            obfuscated.remove(&obfuscation::sha1(info_hash.as_bytes()));
+            dirty.remove(info_hash);
+        }
+
+        reconciled
+    }
+
+    pub async fn resolve_obfuscated(&self, sha_ih: &[u8]) -> Option<String> {
+        let hash: [u8; 20] = sha_ih.try_into().ok()?;
+        self.obfuscated.read().await.get(&hash).cloned()
     }
 
     pub async fn get_scrapes(&self, info_hashes: Vec<String>) -> Vec<ScrapeFile> {
@@ -115,21 +240,80 @@ impl TorrentStore {
         (complete, incomplete)
     }
 
+    // Registers the torrent if unknown torrents are taken on
+    async fn register(&self, torrents: &mut TorrentRecords, info_hash: &str) {
+        if self.register_unknown && !torrents.contains_key(info_hash) {
+            self.obfuscated.write().await.insert(
+                obfuscation::sha1(info_hash.as_bytes()),
+                info_hash.to_string(),
+            );
+            torrents.insert(
+                info_hash.to_string(),
+                Torrent::new(info_hash.to_string(), 0, 0, 0, 0),
+            );
+        }
+    }
+
     pub async fn new_seed(&self, info_hash: String) {
         let mut torrents = self.torrents.write().await;
+        self.register(&mut torrents, &info_hash).await;
         if let Some(t) = torrents.get_mut(&info_hash) {
             t.complete += 1;
             t.incomplete = t.incomplete.saturating_sub(1);
+            self.dirty.lock().insert(info_hash);
         }
     }
 
     pub async fn new_leech(&self, info_hash: String) {
         let mut torrents = self.torrents.write().await;
+        self.register(&mut torrents, &info_hash).await;
         if let Some(t) = torrents.get_mut(&info_hash) {
             t.incomplete += 1;
+            self.dirty.lock().insert(info_hash);
         }
     }
 
+    // Hands out the torrents that changed since they were last taken.
+    // They count as clean from here on, so whatever can't be written
+    // has to be handed back with mark_dirty.
+    pub async fn take_dirty(&self) -> Vec<Torrent> {
+        let torrents = self.torrents.read().await;
+        let dirty = std::mem::take(&mut *self.dirty.lock());
+
+        dirty
+            .iter()
+            .filter_map(|info_hash| torrents.get(info_hash).cloned())
+            .collect()
+    }
+
+    pub fn mark_dirty<'a>(&self, info_hashes: impl IntoIterator<Item = &'a String>) {
+        self.dirty.lock().extend(info_hashes.into_iter().cloned());
+    }
+
+    pub fn dirty_hashes(&self) -> Vec<String> {
+        self.dirty.lock().iter().cloned().collect()
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.lock().len()
+    }
+
+    // Only one flush may run at a time. Every flush that starts
+    // counts towards the generation.
+    pub fn begin_flush(&self) -> Option<Busy> {
+        let busy = Busy::try_start(&self.flushing)?;
+        self.flushes.fetch_add(1, Ordering::AcqRel);
+        Some(busy)
+    }
+
+    pub fn flush_generation(&self) -> u64 {
+        self.flushes.load(Ordering::Acquire)
+    }
+
+    pub fn is_flushing(&self) -> bool {
+        self.flushing.load(Ordering::Acquire)
+    }
+
     /*pub fn undo_snatch(&self, info_hash: String) {
         let mut torrents = self.torrents.write();
         if let Some(t) = torrents.get_mut(&info_hash) {
@@ -209,6 +393,7 @@ pub struct PeerStore {
 }
 
 impl PeerStore {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> PeerStore {
         PeerStore {
             records: Arc::new(RwLock::new(PeerRecords::new())),
@@ -261,6 +446,23 @@ impl PeerStore {
         result
     }
 
+    // Drops the swarms of torrents that no longer exist; returns
+    // the number of seeders and leechers that went with them
+    pub async fn remove_swarms(&self, info_hashes: &[String]) -> (u32, u32) {
+        let mut store = self.records.write().await;
+        let mut seeders = 0;
+        let mut leechers = 0;
+
+        for info_hash in info_hashes {
+            if let Some(sw) = store.remove(info_hash) {
+                seeders += sw.seeders.len() as u32;
+                leechers += sw.leechers.len() as u32;
+            }
+        }
+
+        (seeders, leechers)
+    }
+
     pub async fn promote_leecher(&self, info_hash: String, peer: Peer) {
         let mut store = self.records.write().await;
         if let Some(sw) = store.get_mut(&info_hash) {
@@ -276,46 +478,26 @@ impl PeerStore {
         }
     }
 
-    // Returns a randomized vector of peers to be returned to client
+    // Returns a randomized vector of peers to be returned to client.
+    // Peers whose encryption preferences can't be satisfied by
+    // the requesting client are left out of the selection.
     pub async fn get_peers(
         &self,
         info_hash: String,
         numwant: u32,
+        crypto: Crypto,
     ) -> (Vec<CompactPeerv4>, Vec<CompactPeerv6>) {
         let mut peer_list = PeerList::new();
 
         let store = self.records.read().await;
         if let Some(sw) = store.get(&info_hash) {
-            let seeds: Vec<CompactPeer> = sw
+            let peers = sw
                 .seeders
                 .iter()
-                .map(|p| match p {
-                    Peer::V4(p) => CompactPeer::V4(CompactPeerv4 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                    Peer::V6(p) => CompactPeer::V6(CompactPeerv6 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                })
-                .collect();
-            let leeches: Vec<CompactPeer> = sw
-                .leechers
-                .iter()
-                .map(|p| match p {
-                    Peer::V4(p) => CompactPeer::V4(CompactPeerv4 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                    Peer::V6(p) => CompactPeer::V6(CompactPeerv6 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                })
-                .collect();
-            peer_list.0.extend(seeds);
-            peer_list.0.extend(leeches);
+                .chain(sw.leechers.iter())
+                .filter(|p| p.crypto().compatible_with(crypto))
+                .map(|p| p.to_compact(crypto));
+            peer_list.0.extend(peers);
         }
 
         // Randomized bunch of seeders and leechers
@@ -350,6 +532,178 @@ mod tests {
     use super::*;
 
     #[tokio::test]
+    async fn torrent_storage_resolve_obfuscated() {
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let mut records = TorrentRecords::new();
+        records.insert(
+            info_hash.clone(),
+            Torrent::new(info_hash.clone(), 0, 0, 0, 0),
+        );
+        let torrent_store = TorrentStore::new(records);
+
+        let other = "B2C3D4E5F6G7H8I9J0K1".to_string();
+        let existing = vec![info_hash.clone(), other.clone()].into_iter().collect();
+        let reconciled = torrent_store
+            .reconcile(
+                vec![
+                    Torrent::new(info_hash.clone(), 0, 0, 0, 0),
+                    Torrent::new(other.clone(), 0, 0, 0, 0),
+                ],
+                &existing,
+                torrent_store.flush_generation(),
+            )
+            .await;
+        assert_eq!(reconciled.added, 1);
+
+        let sha_ih = obfuscation::sha1(info_hash.as_bytes());
+        assert_eq!(
+            torrent_store.resolve_obfuscated(&sha_ih).await,
+            Some(info_hash)
+        );
+
+        let sha_ih = obfuscation::sha1(other.as_bytes());
+        assert_eq!(torrent_store.resolve_obfuscated(&sha_ih).await, Some(other));
+    }
+
+    #[tokio::test]
+    async fn torrent_storage_dirty_tracking() {
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let mut records = TorrentRecords::new();
+        records.insert(
+            info_hash.clone(),
+            Torrent::new(info_hash.clone(), 0, 0, 0, 0),
+        );
+        let torrent_store = TorrentStore::new(records);
+        assert!(torrent_store.take_dirty().await.is_empty());
+
+        torrent_store.new_leech(info_hash.clone()).await;
+        torrent_store.new_seed(info_hash.clone()).await;
+        torrent_store.new_leech("unknown".to_string()).await;
+        assert_eq!(torrent_store.dirty_count(), 1);
+
+        let dirty = torrent_store.take_dirty().await;
+        assert_eq!(dirty.len(), 1);
+        assert_eq!(dirty[0].complete, 1);
+        assert_eq!(torrent_store.dirty_count(), 0);
+
+        // A failed flush hands the torrents back
+        torrent_store.mark_dirty(dirty.iter().map(|t| &t.info_hash));
+        assert_eq!(torrent_store.take_dirty().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn torrent_storage_reconcile() {
+        let kept = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let busy = "B2C3D4E5F6G7H8I9J0K1".to_string();
+        let deleted = "C3D4E5F6G7H8I9J0K1L2".to_string();
+        let mut records = TorrentRecords::new();
+        for info_hash in &[&kept, &busy, &deleted] {
+            records.insert(
+                info_hash.to_string(),
+                Torrent::new(info_hash.to_string(), 0, 0, 0, 0),
+            );
+        }
+        let torrent_store = TorrentStore::new(records);
+        torrent_store.new_leech(busy.clone()).await;
+        torrent_store.new_leech(deleted.clone()).await;
+
+        // Local changes to 'busy' win until they're flushed
+        let existing = vec![kept.clone(), busy.clone()].into_iter().collect();
+        let reconciled = torrent_store
+            .reconcile(
+                vec![
+                    Torrent::new(kept.clone(), 5, 0, 0, 0),
+                    Torrent::new(busy.clone(), 5, 0, 0, 0),
+                    Torrent::new(deleted.clone(), 5, 0, 0, 0),
+                ],
+                &existing,
+                torrent_store.flush_generation(),
+            )
+            .await;
+        assert_eq!(reconciled.added, 0);
+        assert_eq!(reconciled.updated, 1);
+        assert_eq!(reconciled.removed, vec![deleted.clone()]);
+
+        let torrents = torrent_store.torrents.read().await;
+        assert_eq!(torrents[&kept].complete, 5);
+        assert_eq!(torrents[&busy].complete, 0);
+        assert!(!torrents.contains_key(&deleted));
+        assert_eq!(torrent_store.dirty_hashes(), vec![busy]);
+
+        let sha_ih = obfuscation::sha1(deleted.as_bytes());
+        assert_eq!(torrent_store.resolve_obfuscated(&sha_ih).await, None);
+    }
+
+    #[tokio::test]
+    async fn torrent_storage_reconcile_during_flush() {
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let mut records = TorrentRecords::new();
+        records.insert(
+            info_hash.clone(),
+            Torrent::new(info_hash.clone(), 0, 0, 0, 0),
+        );
+        let torrent_store = TorrentStore::new(records);
+        let existing: HashSet<String> = vec![info_hash.clone()].into_iter().collect();
+        let stale = vec![Torrent::new(info_hash.clone(), 0, 0, 0, 0)];
+
+        // The rows are loaded, then a flush takes the announce before they're applied
+        let generation = torrent_store.flush_generation();
+        torrent_store.new_leech(info_hash.clone()).await;
+        let flush = torrent_store.begin_flush().unwrap();
+        assert_eq!(torrent_store.take_dirty().await.len(), 1);
+
+        let reconciled = torrent_store
+            .reconcile(stale.clone(), &existing, generation)
+            .await;
+        assert!(reconciled.deferred);
+        assert_eq!(reconciled.updated, 0);
+
+        // The same goes once the flush is over, as the rows still predate it
+        drop(flush);
+        let reconciled = torrent_store.reconcile(stale, &existing, generation).await;
+        assert!(reconciled.deferred);
+        assert_eq!(
+            torrent_store.torrents.read().await[&info_hash].incomplete,
+            1
+        );
+
+        // Rows loaded after the flush are applied
+        let fresh = vec![Torrent::new(info_hash.clone(), 0, 0, 2, 0)];
+        let reconciled = torrent_store
+            .reconcile(fresh, &existing, torrent_store.flush_generation())
+            .await;
+        assert!(!reconciled.deferred);
+        assert_eq!(reconciled.updated, 1);
+        assert_eq!(
+            torrent_store.torrents.read().await[&info_hash].incomplete,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_remove_swarms() {
+        let peer_store = PeerStore::new();
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let peer = Peer::V4(Peerv4 {
+            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
+        });
+
+        peer_store.put_seeder(info_hash.clone(), peer.clone()).await;
+        peer_store.put_leecher(info_hash.clone(), peer).await;
+        let removed = peer_store
+            .remove_swarms(&[info_hash.clone(), "unknown".to_string()])
+            .await;
+        assert_eq!(removed, (1, 1));
+        assert!(peer_store.records.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_put_seeder_new_swarm() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -358,6 +712,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store.put_seeder(info_hash.clone(), peer.clone()).await;
@@ -375,6 +731,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_put_seeder_prior_swarm() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -383,6 +740,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store.put_seeder(info_hash.clone(), peer1).await;
@@ -392,6 +751,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6881,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store
@@ -411,6 +772,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_put_leecher_new_swarm() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -419,6 +781,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store
@@ -438,6 +802,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_put_leecher_prior_swarm() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -446,6 +811,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store.put_seeder(info_hash.clone(), peer1).await;
@@ -455,6 +822,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6881,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store
@@ -474,6 +843,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_remove_seeder() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -482,6 +852,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store.put_seeder(info_hash.clone(), peer.clone()).await;
@@ -503,6 +875,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_remove_leecher() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -511,6 +884,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store
@@ -534,6 +909,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_promote_leecher() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -542,6 +918,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store
@@ -565,6 +943,77 @@ mod tests {
     }
 
     #[tokio::test]
+    async fn memory_peer_storage_get_peers_crypto_filter() {
+        let peer_store = PeerStore::new();
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let plaintext = Peer::V4(Peerv4 {
+            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6893,
+            last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
+        });
+        let encrypted = Peer::V4(Peerv4 {
+            peer_id: "TSRQPONMLKJIHGFEDCBA".to_string(),
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            port: 0,
+            last_announced: Instant::now(),
+            crypto: Crypto::Required,
+            crypto_port: Some(6881),
+        });
+
+        peer_store.put_seeder(info_hash.clone(), plaintext).await;
+        peer_store.put_leecher(info_hash.clone(), encrypted).await;
+
+        let (peers, _) = peer_store
+            .get_peers(info_hash.clone(), 50, Crypto::Required)
+            .await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].port, 6881);
+        assert!(peers[0].requires_crypto);
+
+        let (peers, _) = peer_store
+            .get_peers(info_hash.clone(), 50, Crypto::None)
+            .await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip, Ipv4Addr::new(10, 0, 0, 1));
+
+        let (peers, _) = peer_store.get_peers(info_hash, 50, Crypto::Supported).await;
+        assert_eq!(peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_get_peers_crypto_port() {
+        let peer_store = PeerStore::new();
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let peer = Peer::V4(Peerv4 {
+            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6893,
+            last_announced: Instant::now(),
+            crypto: Crypto::Supported,
+            crypto_port: Some(6881),
+        });
+
+        peer_store.put_seeder(info_hash.clone(), peer).await;
+
+        let (peers, _) = peer_store
+            .get_peers(info_hash.clone(), 50, Crypto::None)
+            .await;
+        assert_eq!(peers[0].port, 6893);
+
+        let (peers, _) = peer_store
+            .get_peers(info_hash.clone(), 50, Crypto::Supported)
+            .await;
+        assert_eq!(peers[0].port, 6881);
+
+        let (peers, _) = peer_store.get_peers(info_hash, 50, Crypto::Required).await;
+        assert_eq!(peers[0].port, 6881);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn memory_peer_storage_update_peer() {
         let peer_store = PeerStore::new();
         let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
@@ -573,6 +1022,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store
@@ -584,6 +1035,8 @@ mod tests {
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            crypto: Crypto::None,
+            crypto_port: None,
         });
 
         peer_store
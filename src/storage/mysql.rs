@@ -1,54 +1,279 @@
+use crate::config;
+use crate::network::ban::{self, Ban};
 use crate::storage;
+use crate::storage::backend::TorrentBackend;
 use mysql::prelude::*;
 use mysql::*;
 
-pub fn get_torrents(pool: Pool) -> Result<storage::TorrentRecords> {
-    let mut conn = pool.get_conn()?;
+// Migration 1 is the schema Tyto used to ship as schema.sql, so that
+// databases created from it are picked up as they are. DDL can't be
+// rolled back in MySQL, so a migration that fails halfway has to be
+// cleaned up by hand before it's run again.
+const MIGRATIONS: &[&str] = &[
+    // 1: torrents
+    "CREATE TABLE IF NOT EXISTS torrents (
+        info_hash VARCHAR(50) NOT NULL UNIQUE,
+        complete INT NOT NULL,
+        downloaded INT NOT NULL,
+        incomplete INT NOT NULL,
+        balance BIGINT NOT NULL,
+        PRIMARY KEY (info_hash)
+    ) ENGINE = InnoDB;",
+    // 2: change tracking for reconciliation, and bans
+    "ALTER TABLE torrents
+        ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+        ADD INDEX (updated_at);
+    CREATE TABLE IF NOT EXISTS bans (
+        network VARCHAR(100) NOT NULL,
+        expires_at TIMESTAMP NULL DEFAULT NULL,
+        PRIMARY KEY (network)
+    ) ENGINE = InnoDB;",
+    // 3: info hashes are 40 hex characters. Longer ones have to be
+    // removed first, as they can't be narrowed without losing data.
+    "ALTER TABLE torrents MODIFY info_hash CHAR(40) NOT NULL;",
+];
 
-    let mut torrents = storage::TorrentRecords::new();
+// Kept apart from the migrations, as databases from before
+// them have to be able to record the first one
+const VERSION_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+    version INT UNSIGNED NOT NULL,
+    applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (version)
+) ENGINE = InnoDB";
 
-    let selected_torrents = conn.query_map(
-        "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents",
-        |(info_hash, complete, downloaded, incomplete, balance)| storage::Torrent {
-            info_hash,
-            complete,
-            downloaded,
-            incomplete,
-            balance,
-        },
-    )?;
+pub struct MySql {
+    pool: Pool,
+}
+
+impl MySql {
+    // A password from the configuration takes the place of one in the URL
+    pub fn connect(storage: &config::Storage) -> std::result::Result<MySql, String> {
+        let opts =
+            Opts::from_url(&storage.path).map_err(|e| format!("Invalid database URL: {}", e))?;
+        let opts = match storage.password()? {
+            Some(password) => OptsBuilder::from_opts(opts).pass(Some(password)).into(),
+            None => opts,
+        };
 
-    for sel in selected_torrents {
-        torrents.insert(sel.info_hash.clone(), sel);
+        let pool = Pool::new(opts).map_err(|e| format!("Could not connect to database: {}", e))?;
+        Ok(MySql { pool })
     }
 
-    Ok(torrents)
-}
+    fn select_torrents(&self, query: &str, since: Option<u64>) -> Result<Vec<storage::Torrent>> {
+        let mut conn = self.pool.get_conn()?;
+
+        conn.exec_map(
+            query,
+            since.map_or(Params::Empty, |since| Params::from((since,))),
+            |(info_hash, complete, downloaded, incomplete, balance)| storage::Torrent {
+                info_hash,
+                complete,
+                downloaded,
+                incomplete,
+                balance,
+            },
+        )
+    }
+
+    fn select_info_hashes(&self) -> Result<Vec<String>> {
+        let mut conn = self.pool.get_conn()?;
+        conn.query("SELECT info_hash FROM torrents")
+    }
 
-pub fn flush_torrents(pool: Pool, torrents: Vec<storage::Torrent>) -> Result<()> {
-    // Flushing should be accompanied by a lock on peer and torrent records
-    let mut conn = pool.get_conn()?;
-
-    let params = torrents.iter().map(|torrent| {
-        params! {
-            "info_hash" => &torrent.info_hash,
-            "complete" => torrent.complete,
-            "downloaded" => torrent.downloaded,
-            "incomplete" => torrent.incomplete,
-            "balance" => torrent.balance,
+    // Each batch is written in a single transaction. Rows that didn't
+    // change keep their updated_at, as MySQL only bumps it on a change.
+    fn update_torrents(&self, torrents: &[storage::Torrent]) -> Result<()> {
+        let mut conn = self.pool.get_conn()?;
+        let mut transaction = conn.start_transaction(TxOpts::default())?;
+
+        let params = torrents.iter().map(|torrent| {
+            params! {
+                "info_hash" => &torrent.info_hash,
+                "complete" => torrent.complete,
+                "downloaded" => torrent.downloaded,
+                "incomplete" => torrent.incomplete,
+                "balance" => torrent.balance,
+            }
+        });
+
+        transaction.exec_batch(
+            r"UPDATE torrents SET
+                    complete=:complete,
+                    downloaded=:downloaded,
+                    incomplete=:incomplete,
+                    balance=:balance
+                WHERE info_hash=:info_hash",
+            params,
+        )?;
+
+        transaction.commit()
+    }
+
+    fn select_schema_version(&self) -> Result<u32> {
+        let mut conn = self.pool.get_conn()?;
+        let exists: Option<bool> = conn.query_first(
+            "SELECT COUNT(*) > 0 FROM information_schema.tables
+                WHERE table_schema = DATABASE() AND table_name = 'schema_version'",
+        )?;
+        if exists != Some(true) {
+            return Ok(0);
         }
-    });
-
-    conn.exec_batch(
-        r"INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
-                    VALUES (:info_hash, :complete, :downloaded, :incomplete, :balance)
-                    ON DUPLICATE KEY UPDATE 
-                        complete=:complete, 
-                        downloaded=:downloaded, 
-                        incomplete=:incomplete, 
-                        balance=:balance",
-        params,
-    )?;
-
-    Ok(())
+
+        let version: Option<u32> =
+            conn.query_first("SELECT COALESCE(MAX(version), 0) FROM schema_version")?;
+        Ok(version.unwrap_or(0))
+    }
+
+    fn select_blank(&self) -> Result<bool> {
+        let blank: Option<bool> = self.pool.get_conn()?.query_first(
+            "SELECT COUNT(*) = 0 FROM information_schema.tables WHERE table_schema = DATABASE()",
+        )?;
+        Ok(blank == Some(true))
+    }
+
+    fn run_migration(&self, version: u32, migration: &str) -> Result<()> {
+        let mut conn = self.pool.get_conn()?;
+        conn.query_drop(VERSION_TABLE)?;
+        conn.query_drop(migration)?;
+        conn.exec_drop(
+            "INSERT INTO schema_version (version) VALUES (?)",
+            (version,),
+        )
+    }
+
+    // Bans are stored with the same syntax as entries in a ban file,
+    // with expired ones being left out by the query
+    fn select_bans(&self) -> Result<Vec<Ban>> {
+        let mut conn = self.pool.get_conn()?;
+
+        let rows: Vec<(String, Option<u64>)> = conn.query(
+            "SELECT network, CAST(UNIX_TIMESTAMP(expires_at) AS UNSIGNED) FROM bans
+                WHERE expires_at IS NULL OR expires_at > NOW()",
+        )?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(network, expires)| match ban::parse_entry(&network) {
+                Some(parsed) => Some(parsed.with_expiry(expires)),
+                None => {
+                    warn!("Skipping invalid ban in database: {}", network);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+impl TorrentBackend for MySql {
+    fn name(&self) -> &'static str {
+        "mysql"
+    }
+
+    fn load_torrents(&self) -> std::result::Result<storage::TorrentRecords, String> {
+        let torrents = self
+            .select_torrents(
+                "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents",
+                None,
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(torrents
+            .into_iter()
+            .map(|torrent| (torrent.info_hash.clone(), torrent))
+            .collect())
+    }
+
+    fn load_changed_since(&self, since: u64) -> std::result::Result<Vec<storage::Torrent>, String> {
+        self.select_torrents(
+            "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents
+                WHERE updated_at >= FROM_UNIXTIME(?)",
+            Some(since),
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    fn load_info_hashes(&self) -> std::result::Result<Vec<String>, String> {
+        self.select_info_hashes().map_err(|e| e.to_string())
+    }
+
+    fn flush_torrents(&self, torrents: &[storage::Torrent]) -> std::result::Result<(), String> {
+        self.update_torrents(torrents).map_err(|e| e.to_string())
+    }
+
+    fn load_bans(&self) -> std::result::Result<Vec<Ban>, String> {
+        self.select_bans().map_err(|e| e.to_string())
+    }
+
+    fn health_check(&self) -> std::result::Result<(), String> {
+        let mut conn = self.pool.get_conn().map_err(|e| e.to_string())?;
+        conn.query_drop("SELECT 1").map_err(|e| e.to_string())
+    }
+
+    fn migrations(&self) -> &'static [&'static str] {
+        MIGRATIONS
+    }
+
+    fn schema_version(&self) -> std::result::Result<u32, String> {
+        self.select_schema_version().map_err(|e| e.to_string())
+    }
+
+    fn is_blank(&self) -> std::result::Result<bool, String> {
+        self.select_blank().map_err(|e| e.to_string())
+    }
+
+    fn apply_migration(&self, version: u32, migration: &str) -> std::result::Result<(), String> {
+        self.run_migration(version, migration)
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::migrations;
+
+    // This needs a database to write to, e.g.
+    // TYTO_TEST_MYSQL='mysql://tyto@localhost/tyto_test' cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn mysql_upgrades_baseline_schema() {
+        let path = std::env::var("TYTO_TEST_MYSQL").expect("TYTO_TEST_MYSQL is not set");
+        let backend = MySql::connect(&config::Storage {
+            backend: "mysql".to_string(),
+            path,
+            ..config::Storage::default()
+        })
+        .unwrap();
+
+        // A table as created by the old schema.sql, with a torrent in it
+        let mut conn = backend.pool.get_conn().unwrap();
+        conn.query_drop("DROP TABLE IF EXISTS torrents, bans, schema_version")
+            .unwrap();
+        conn.query_drop(MIGRATIONS[0]).unwrap();
+        conn.query_drop(
+            "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
+                VALUES ('A1B2C3D4E5F6G7H8I9J0', 1, 2, 3, 4)",
+        )
+        .unwrap();
+        drop(conn);
+
+        assert_eq!(backend.schema_version().unwrap(), 0);
+        assert!(migrations::check(&backend).is_err());
+        assert_eq!(migrations::migrate(&backend).unwrap(), (0, 3));
+        assert!(migrations::check(&backend).is_ok());
+
+        let torrents = backend.load_torrents().unwrap();
+        assert_eq!(torrents["A1B2C3D4E5F6G7H8I9J0"].balance, 4);
+        assert_eq!(backend.load_changed_since(0).unwrap().len(), 1);
+        assert!(backend.load_bans().unwrap().is_empty());
+
+        let mut updated = torrents["A1B2C3D4E5F6G7H8I9J0"].clone();
+        updated.complete = 5;
+        backend.flush_torrents(&[updated]).unwrap();
+        assert_eq!(
+            backend.load_torrents().unwrap()["A1B2C3D4E5F6G7H8I9J0"].complete,
+            5
+        );
+    }
 }
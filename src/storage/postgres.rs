@@ -0,0 +1,396 @@
+use std::str::FromStr;
+
+use parking_lot::Mutex;
+use postgres::{Client, NoTls};
+
+use crate::config;
+use crate::network::ban::{self, Ban};
+use crate::storage::backend::TorrentBackend;
+use crate::storage::{Torrent, TorrentRecords};
+
+// Info hashes are stored hex-encoded, and counters as BIGINT,
+// since Postgres has no unsigned types.
+// Notices about tables that already exist are left out of the log.
+// updated_at is kept current by a trigger, so that rows changed
+// outside of Tyto are picked up by reconciliation as well.
+const MIGRATIONS: &[&str] = &[
+    // 1: torrents, bans and the version table
+    "SET client_min_messages = warning;
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER NOT NULL PRIMARY KEY,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE TABLE IF NOT EXISTS torrents (
+        info_hash CHAR(40) NOT NULL PRIMARY KEY,
+        complete BIGINT NOT NULL,
+        downloaded BIGINT NOT NULL,
+        incomplete BIGINT NOT NULL,
+        balance BIGINT NOT NULL,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS torrents_updated_at ON torrents (updated_at);
+    CREATE TABLE IF NOT EXISTS bans (
+        network VARCHAR(100) NOT NULL PRIMARY KEY,
+        expires_at TIMESTAMPTZ
+    );",
+    // 2: keep updated_at current for rows changed outside of Tyto
+    "CREATE OR REPLACE FUNCTION torrents_touch() RETURNS trigger AS $$
+        BEGIN
+            NEW.updated_at = now();
+            RETURN NEW;
+        END;
+    $$ LANGUAGE plpgsql;
+    CREATE TRIGGER torrents_touch BEFORE UPDATE ON torrents
+        FOR EACH ROW EXECUTE FUNCTION torrents_touch();",
+];
+
+// The client is reconnected if the server closes the connection
+pub struct Postgres {
+    config: postgres::Config,
+    client: Mutex<Client>,
+}
+
+impl Postgres {
+    // A password from the configuration takes the place of one in the URL
+    pub fn connect(storage: &config::Storage) -> Result<Postgres, String> {
+        let mut config = postgres::Config::from_str(&storage.path)
+            .map_err(|e| format!("Invalid database URL: {}", e))?;
+        if let Some(password) = storage.password()? {
+            config.password(password);
+        }
+
+        let client = config
+            .connect(NoTls)
+            .map_err(|e| format!("Could not connect to database: {}", e))?;
+
+        Ok(Postgres {
+            config,
+            client: Mutex::new(client),
+        })
+    }
+
+    fn client(&self) -> Result<parking_lot::MutexGuard<'_, Client>, postgres::Error> {
+        let mut client = self.client.lock();
+        if client.is_closed() {
+            *client = self.config.connect(NoTls)?;
+        }
+
+        Ok(client)
+    }
+
+    fn select_torrents(&self, since: Option<u64>) -> Result<Vec<Torrent>, postgres::Error> {
+        let mut client = self.client()?;
+        let rows = match since {
+            Some(since) => client.query(
+                "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents
+                    WHERE updated_at >= to_timestamp($1)",
+                &[&(since as f64)],
+            )?,
+            None => client.query(
+                "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents",
+                &[],
+            )?,
+        };
+
+        Ok(rows
+            .iter()
+            .map(|row| Torrent {
+                info_hash: row.get(0),
+                complete: row.get::<_, i64>(1) as u32,
+                downloaded: row.get::<_, i64>(2) as u32,
+                incomplete: row.get::<_, i64>(3) as u32,
+                balance: row.get::<_, i64>(4) as u32,
+            })
+            .collect())
+    }
+
+    fn select_info_hashes(&self) -> Result<Vec<String>, postgres::Error> {
+        let rows = self
+            .client()?
+            .query("SELECT info_hash FROM torrents", &[])?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    // The whole batch is sent as arrays in a single statement
+    fn update_torrents(&self, torrents: &[Torrent]) -> Result<(), postgres::Error> {
+        let info_hashes: Vec<&str> = torrents.iter().map(|t| t.info_hash.as_str()).collect();
+        let column = |f: fn(&Torrent) -> u32| -> Vec<i64> {
+            torrents.iter().map(|t| i64::from(f(t))).collect()
+        };
+        let complete = column(|t| t.complete);
+        let downloaded = column(|t| t.downloaded);
+        let incomplete = column(|t| t.incomplete);
+        let balance = column(|t| t.balance);
+
+        let mut client = self.client()?;
+        let mut transaction = client.transaction()?;
+        transaction.execute(
+            "UPDATE torrents SET
+                    complete = batch.complete,
+                    downloaded = batch.downloaded,
+                    incomplete = batch.incomplete,
+                    balance = batch.balance,
+                    updated_at = now()
+                FROM UNNEST($1::CHAR(40)[], $2::BIGINT[], $3::BIGINT[], $4::BIGINT[], $5::BIGINT[])
+                    AS batch (info_hash, complete, downloaded, incomplete, balance)
+                WHERE torrents.info_hash = batch.info_hash
+                    AND (torrents.complete, torrents.downloaded, torrents.incomplete, torrents.balance)
+                        IS DISTINCT FROM
+                        (batch.complete, batch.downloaded, batch.incomplete, batch.balance)",
+            &[&info_hashes, &complete, &downloaded, &incomplete, &balance],
+        )?;
+
+        transaction.commit()
+    }
+
+    fn select_schema_version(&self) -> Result<u32, postgres::Error> {
+        let mut client = self.client()?;
+        let exists: bool = client
+            .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])?
+            .get(0);
+        if !exists {
+            return Ok(0);
+        }
+
+        let version: i32 = client
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])?
+            .get(0);
+        Ok(version as u32)
+    }
+
+    fn select_blank(&self) -> Result<bool, postgres::Error> {
+        Ok(self
+            .client()?
+            .query_one(
+                "SELECT count(*) = 0 FROM information_schema.tables
+                    WHERE table_schema = current_schema()",
+                &[],
+            )?
+            .get(0))
+    }
+
+    fn run_migration(&self, version: u32, migration: &str) -> Result<(), postgres::Error> {
+        let mut client = self.client()?;
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute(migration)?;
+        transaction.execute(
+            "INSERT INTO schema_version (version) VALUES ($1)",
+            &[&(version as i32)],
+        )?;
+
+        transaction.commit()
+    }
+
+    // Bans use the same syntax as entries in a ban file
+    fn select_bans(&self) -> Result<Vec<Ban>, postgres::Error> {
+        let rows = self.client()?.query(
+            "SELECT network, EXTRACT(EPOCH FROM expires_at)::BIGINT FROM bans
+                WHERE expires_at IS NULL OR expires_at > now()",
+            &[],
+        )?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let network: String = row.get(0);
+                let expires: Option<i64> = row.get(1);
+                match ban::parse_entry(&network) {
+                    Some(parsed) => Some(parsed.with_expiry(expires.map(|e| e.max(0) as u64))),
+                    None => {
+                        warn!("Skipping invalid ban in database: {}", network);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+}
+
+impl TorrentBackend for Postgres {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn load_torrents(&self) -> Result<TorrentRecords, String> {
+        let torrents = self.select_torrents(None).map_err(|e| e.to_string())?;
+
+        Ok(torrents
+            .into_iter()
+            .map(|torrent| (torrent.info_hash.clone(), torrent))
+            .collect())
+    }
+
+    fn load_changed_since(&self, since: u64) -> Result<Vec<Torrent>, String> {
+        self.select_torrents(Some(since)).map_err(|e| e.to_string())
+    }
+
+    fn load_info_hashes(&self) -> Result<Vec<String>, String> {
+        self.select_info_hashes().map_err(|e| e.to_string())
+    }
+
+    fn flush_torrents(&self, torrents: &[Torrent]) -> Result<(), String> {
+        self.update_torrents(torrents).map_err(|e| e.to_string())
+    }
+
+    fn load_bans(&self) -> Result<Vec<Ban>, String> {
+        self.select_bans().map_err(|e| e.to_string())
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        self.client()
+            .and_then(|mut client| client.simple_query("SELECT 1"))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn migrations(&self) -> &'static [&'static str] {
+        MIGRATIONS
+    }
+
+    fn schema_version(&self) -> Result<u32, String> {
+        self.select_schema_version().map_err(|e| e.to_string())
+    }
+
+    fn is_blank(&self) -> Result<bool, String> {
+        self.select_blank().map_err(|e| e.to_string())
+    }
+
+    fn apply_migration(&self, version: u32, migration: &str) -> Result<(), String> {
+        self.run_migration(version, migration)
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::IpAddr;
+
+    use crate::storage::migrations;
+
+    // These need a database to write to, e.g.
+    // TYTO_TEST_POSTGRES='postgres://tyto@localhost/tyto_test' cargo test -- --ignored
+    fn backend() -> Postgres {
+        let path = std::env::var("TYTO_TEST_POSTGRES").expect("TYTO_TEST_POSTGRES is not set");
+        let backend = Postgres::connect(&config::Storage {
+            backend: "postgres".to_string(),
+            path,
+            ..config::Storage::default()
+        })
+        .unwrap();
+        migrations::migrate(&backend).unwrap();
+        backend
+            .client()
+            .unwrap()
+            .batch_execute("TRUNCATE torrents, bans")
+            .unwrap();
+
+        backend
+    }
+
+    #[test]
+    #[ignore]
+    fn postgres_backend() {
+        let backend = backend();
+        assert!(backend.health_check().is_ok());
+        assert!(migrations::check(&backend).is_ok());
+        assert!(backend.load_torrents().unwrap().is_empty());
+
+        backend
+            .client()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance) VALUES
+                    ('a1b2c3d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0', 0, 0, 0, 0),
+                    ('b2c3d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0a1', 0, 0, 0, 0)",
+            )
+            .unwrap();
+
+        // Torrents that aren't in the database aren't added by a flush
+        let torrent = Torrent::new(
+            "a1b2c3d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0".to_string(),
+            1,
+            2,
+            3,
+            u32::MAX,
+        );
+        let deleted = Torrent::new(
+            "c3d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0a1b2".to_string(),
+            0,
+            0,
+            0,
+            0,
+        );
+        backend.flush_torrents(&[torrent.clone(), deleted]).unwrap();
+
+        let mut updated = torrent;
+        updated.complete = 5;
+        backend.flush_torrents(&[updated]).unwrap();
+
+        let torrents = backend.load_torrents().unwrap();
+        assert_eq!(torrents.len(), 2);
+        assert_eq!(
+            torrents["a1b2c3d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0"].complete,
+            5
+        );
+        assert_eq!(
+            torrents["a1b2c3d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0"].balance,
+            u32::MAX
+        );
+
+        assert_eq!(backend.load_changed_since(0).unwrap().len(), 2);
+        assert_eq!(backend.load_info_hashes().unwrap().len(), 2);
+        assert!(backend
+            .load_changed_since(u64::from(u32::MAX))
+            .unwrap()
+            .is_empty());
+
+        // Rows edited by hand are picked up as well
+        backend
+            .client()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance, updated_at)
+                    VALUES ('d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0a1b2c3', 0, 0, 0, 0, to_timestamp(0))",
+            )
+            .unwrap();
+        let since = ban::now();
+        let changed = |since| -> Vec<String> {
+            backend
+                .load_changed_since(since)
+                .unwrap()
+                .into_iter()
+                .map(|t| t.info_hash)
+                .collect()
+        };
+        assert!(!changed(since).contains(&"d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0a1b2c3".to_string()));
+        backend
+            .client()
+            .unwrap()
+            .batch_execute(
+                "UPDATE torrents SET complete = 7 WHERE info_hash = 'd4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0a1b2c3'",
+            )
+            .unwrap();
+        assert!(changed(since).contains(&"d4e5f6a7b8c9d0a1b2c3d4e5f6a7b8c9d0a1b2c3".to_string()));
+
+        backend
+            .client()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO bans (network, expires_at) VALUES
+                    ('203.0.113.0/24', NULL),
+                    ('198.51.100.1', to_timestamp(1)),
+                    ('not an address', NULL)",
+            )
+            .unwrap();
+
+        let bans = backend.load_bans().unwrap();
+        assert_eq!(bans.len(), 1);
+
+        let list = ban::BanList::new();
+        list.replace(bans);
+        assert!(list.is_banned("203.0.113.9".parse::<IpAddr>().unwrap()));
+    }
+}
@@ -0,0 +1,298 @@
+// Swarms are saved to disk so that a restarted tracker can hand out
+// peers right away instead of waiting for every client to announce.
+// Instants can't outlive the process, so announce times are saved
+// as unix timestamps and turned back into instants when loading.
+// Torrents are saved as well, along with those not flushed yet, so
+// that the tracker can start while its database is unavailable.
+
+use std::fs;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bittorrent::{Crypto, Peer, Peerv4, Peerv6};
+use crate::storage::{PeerStore, Swarm, Torrent, TorrentRecords, TorrentStore};
+
+// Bumped whenever the layout below changes
+const VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedPeer {
+    peer_id: String,
+    ip: IpAddr,
+    port: u16,
+    crypto: Crypto,
+    crypto_port: Option<u16>,
+    last_announced: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedSwarm {
+    info_hash: String,
+    seeders: Vec<SavedPeer>,
+    leechers: Vec<SavedPeer>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    version: u32,
+    swarms: Vec<SavedSwarm>,
+    torrents: Vec<Torrent>,
+    dirty: Vec<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl SavedPeer {
+    fn new(peer: &Peer, now: u64) -> SavedPeer {
+        let (peer_id, ip, port, last_announced, crypto, crypto_port) = match peer {
+            Peer::V4(p) => (
+                &p.peer_id,
+                IpAddr::V4(p.ip),
+                p.port,
+                p.last_announced,
+                p.crypto,
+                p.crypto_port,
+            ),
+            Peer::V6(p) => (
+                &p.peer_id,
+                IpAddr::V6(p.ip),
+                p.port,
+                p.last_announced,
+                p.crypto,
+                p.crypto_port,
+            ),
+        };
+
+        SavedPeer {
+            peer_id: peer_id.clone(),
+            ip,
+            port,
+            crypto,
+            crypto_port,
+            last_announced: now.saturating_sub(last_announced.elapsed().as_secs()),
+        }
+    }
+
+    // Peers that would have been reaped by now are left out
+    fn into_peer(self, now: u64, peer_timeout: Duration) -> Option<Peer> {
+        let age = Duration::from_secs(now.saturating_sub(self.last_announced));
+        if age >= peer_timeout {
+            return None;
+        }
+        let last_announced = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+
+        Some(match self.ip {
+            IpAddr::V4(ip) => Peer::V4(Peerv4 {
+                peer_id: self.peer_id,
+                ip,
+                port: self.port,
+                last_announced,
+                crypto: self.crypto,
+                crypto_port: self.crypto_port,
+            }),
+            IpAddr::V6(ip) => Peer::V6(Peerv6 {
+                peer_id: self.peer_id,
+                ip,
+                port: self.port,
+                last_announced,
+                crypto: self.crypto,
+                crypto_port: self.crypto_port,
+            }),
+        })
+    }
+}
+
+impl Snapshot {
+    pub async fn capture(peer_store: &PeerStore, torrent_store: &TorrentStore) -> Snapshot {
+        let now = now();
+        let torrents = torrent_store
+            .torrents
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect();
+        let dirty = torrent_store.dirty_hashes();
+        let records = peer_store.records.read().await;
+
+        let swarms = records
+            .iter()
+            .filter(|(_, swarm)| !swarm.seeders.is_empty() || !swarm.leechers.is_empty())
+            .map(|(info_hash, swarm)| SavedSwarm {
+                info_hash: info_hash.clone(),
+                seeders: swarm
+                    .seeders
+                    .iter()
+                    .map(|p| SavedPeer::new(p, now))
+                    .collect(),
+                leechers: swarm
+                    .leechers
+                    .iter()
+                    .map(|p| SavedPeer::new(p, now))
+                    .collect(),
+            })
+            .collect();
+
+        Snapshot {
+            version: VERSION,
+            swarms,
+            torrents,
+            dirty,
+        }
+    }
+
+    // Hands over the torrents, and which of them still need to be flushed
+    pub fn take_torrents(&mut self) -> (TorrentRecords, Vec<String>) {
+        let torrents = std::mem::take(&mut self.torrents)
+            .into_iter()
+            .map(|torrent| (torrent.info_hash.clone(), torrent))
+            .collect();
+
+        (torrents, std::mem::take(&mut self.dirty))
+    }
+
+    pub fn peers(&self) -> usize {
+        self.swarms
+            .iter()
+            .map(|swarm| swarm.seeders.len() + swarm.leechers.len())
+            .sum()
+    }
+
+    // The file is replaced in one step, so a crash while
+    // writing can't leave a truncated snapshot behind
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let encoded = bincode::serialize(self).map_err(|e| e.to_string())?;
+        let partial = format!("{}.partial", path);
+        fs::write(&partial, encoded).map_err(|e| format!("{}: {}", partial, e))?;
+        fs::rename(&partial, path).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn read(path: &str) -> Result<Snapshot, String> {
+        let encoded = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let snapshot: Snapshot = bincode::deserialize(&encoded).map_err(|e| e.to_string())?;
+        if snapshot.version != VERSION {
+            return Err(format!(
+                "{}: unsupported snapshot version {}",
+                path, snapshot.version
+            ));
+        }
+
+        Ok(snapshot)
+    }
+
+    // Returns the number of seeders and leechers that were restored
+    pub async fn restore(self, peer_store: &PeerStore, peer_timeout: Duration) -> (u32, u32) {
+        let now = now();
+        let mut records = peer_store.records.write().await;
+        let mut seeders = 0;
+        let mut leechers = 0;
+
+        for saved in self.swarms {
+            let mut swarm = Swarm::new();
+            for peer in saved.seeders {
+                if let Some(peer) = peer.into_peer(now, peer_timeout) {
+                    swarm.add_seeder(peer);
+                    seeders += 1;
+                }
+            }
+            for peer in saved.leechers {
+                if let Some(peer) = peer.into_peer(now, peer_timeout) {
+                    swarm.add_leecher(peer);
+                    leechers += 1;
+                }
+            }
+
+            if !swarm.seeders.is_empty() || !swarm.leechers.is_empty() {
+                records.insert(saved.info_hash, swarm);
+            }
+        }
+
+        (seeders, leechers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn peer(peer_id: &str, age: u64) -> Peer {
+        Peer::V4(Peerv4 {
+            peer_id: peer_id.to_string(),
+            ip: Ipv4Addr::new(127, 0, 0, 1),
+            port: 6881,
+            last_announced: Instant::now() - Duration::from_secs(age),
+            crypto: Crypto::Supported,
+            crypto_port: Some(6882),
+        })
+    }
+
+    #[tokio::test]
+    async fn snapshot_round_trip() {
+        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let store = PeerStore::new();
+        store.put_seeder(info_hash.clone(), peer("fresh", 10)).await;
+        store
+            .put_leecher(info_hash.clone(), peer("stale", 600))
+            .await;
+
+        let path = std::env::temp_dir().join(format!("tyto-test-{}.snapshot", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut records = TorrentRecords::new();
+        records.insert(
+            info_hash.clone(),
+            Torrent::new(info_hash.clone(), 0, 0, 0, 0),
+        );
+        let torrent_store = TorrentStore::new(records);
+        torrent_store.new_leech(info_hash.clone()).await;
+
+        let snapshot = Snapshot::capture(&store, &torrent_store).await;
+        assert_eq!(snapshot.peers(), 2);
+        snapshot.write(path).unwrap();
+
+        let restored = PeerStore::new();
+        let mut snapshot = Snapshot::read(path).unwrap();
+        let (torrents, dirty) = snapshot.take_torrents();
+        assert_eq!(torrents[&info_hash].incomplete, 1);
+        assert_eq!(dirty, vec![info_hash.clone()]);
+
+        let counts = snapshot.restore(&restored, Duration::from_secs(300)).await;
+        assert_eq!(counts, (1, 0));
+
+        let records = restored.records.read().await;
+        let swarm = &records[&info_hash];
+        assert!(swarm.leechers.is_empty());
+        match swarm.seeders.iter().next().unwrap() {
+            Peer::V4(p) => {
+                assert_eq!(p.peer_id, "fresh");
+                assert_eq!(p.crypto_port, Some(6882));
+                let age = p.last_announced.elapsed().as_secs();
+                assert!((9..=11).contains(&age));
+            }
+            Peer::V6(_) => panic!("restored as IPv6"),
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_rejects_garbage() {
+        let path = std::env::temp_dir().join(format!("tyto-test-{}.garbage", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, b"not a snapshot").unwrap();
+        assert!(Snapshot::read(path).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        assert!(Snapshot::read(path).is_err());
+    }
+}
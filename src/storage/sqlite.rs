@@ -0,0 +1,364 @@
+use std::convert::TryFrom;
+
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+
+use crate::config;
+use crate::network::ban::{self, Ban};
+use crate::storage::backend::TorrentBackend;
+use crate::storage::{Torrent, TorrentRecords};
+
+// Times are kept as unix timestamps. updated_at moves forward whenever
+// a row changes, whether through a flush or by hand, so that
+// reconciliation picks up edits made outside of Tyto.
+const MIGRATIONS: &[&str] = &[
+    // 1: torrents, bans and the version table
+    "CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER NOT NULL PRIMARY KEY,
+        applied_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+    );
+    CREATE TABLE IF NOT EXISTS torrents (
+        info_hash CHAR(40) NOT NULL PRIMARY KEY,
+        complete INTEGER NOT NULL,
+        downloaded INTEGER NOT NULL,
+        incomplete INTEGER NOT NULL,
+        balance INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+    );
+    CREATE INDEX IF NOT EXISTS torrents_updated_at ON torrents (updated_at);
+    CREATE TABLE IF NOT EXISTS bans (
+        network TEXT NOT NULL PRIMARY KEY,
+        expires_at INTEGER
+    );",
+    // 2: keep updated_at current for rows changed outside of Tyto
+    "CREATE TRIGGER IF NOT EXISTS torrents_touch AFTER UPDATE ON torrents
+        FOR EACH ROW WHEN NEW.updated_at = OLD.updated_at
+        BEGIN
+            UPDATE torrents SET updated_at = strftime('%s', 'now')
+                WHERE info_hash = NEW.info_hash;
+        END;",
+];
+
+// A single connection is shared, as SQLite only allows one writer at a time
+pub struct Sqlite {
+    conn: Mutex<Connection>,
+}
+
+impl Sqlite {
+    // The database file is created if it doesn't exist, and
+    // its schema by the migrations
+    pub fn open(storage: &config::Storage) -> Result<Sqlite, String> {
+        let conn = Connection::open(&storage.path)
+            .map_err(|e| format!("Could not open database {}: {}", storage.path, e))?;
+
+        Ok(Sqlite {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn select_torrents(&self, since: i64) -> rusqlite::Result<Vec<Torrent>> {
+        let conn = self.conn.lock();
+        let mut statement = conn.prepare_cached(
+            "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents
+                WHERE updated_at >= ?1",
+        )?;
+
+        let rows = statement.query_map(params![since], |row| {
+            Ok(Torrent {
+                info_hash: row.get(0)?,
+                complete: row.get(1)?,
+                downloaded: row.get(2)?,
+                incomplete: row.get(3)?,
+                balance: row.get(4)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    fn select_info_hashes(&self) -> rusqlite::Result<Vec<String>> {
+        let conn = self.conn.lock();
+        let mut statement = conn.prepare_cached("SELECT info_hash FROM torrents")?;
+        let rows = statement.query_map([], |row| row.get(0))?;
+
+        rows.collect()
+    }
+
+    fn update_torrents(&self, torrents: &[Torrent]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock();
+        let transaction = conn.transaction()?;
+
+        {
+            let mut statement = transaction.prepare_cached(
+                "UPDATE torrents SET
+                        complete = ?2,
+                        downloaded = ?3,
+                        incomplete = ?4,
+                        balance = ?5,
+                        updated_at = strftime('%s', 'now')
+                    WHERE info_hash = ?1
+                        AND (complete != ?2
+                            OR downloaded != ?3
+                            OR incomplete != ?4
+                            OR balance != ?5)",
+            )?;
+
+            for torrent in torrents {
+                statement.execute(params![
+                    torrent.info_hash,
+                    torrent.complete,
+                    torrent.downloaded,
+                    torrent.incomplete,
+                    torrent.balance,
+                ])?;
+            }
+        }
+
+        transaction.commit()
+    }
+
+    fn select_schema_version(&self) -> rusqlite::Result<u32> {
+        let conn = self.conn.lock();
+        let exists: bool = conn.query_row(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(0);
+        }
+
+        conn.query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    fn select_blank(&self) -> rusqlite::Result<bool> {
+        self.conn.lock().query_row(
+            "SELECT count(*) = 0 FROM sqlite_master WHERE type = 'table'",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    fn run_migration(&self, version: u32, migration: &str) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock();
+        let transaction = conn.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            params![version],
+        )?;
+
+        transaction.commit()
+    }
+
+    // Bans use the same syntax as entries in a ban file
+    fn select_bans(&self) -> rusqlite::Result<Vec<Ban>> {
+        let conn = self.conn.lock();
+        let mut statement = conn.prepare_cached(
+            "SELECT network, expires_at FROM bans
+                WHERE expires_at IS NULL OR expires_at > strftime('%s', 'now')",
+        )?;
+
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<u64>>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(network, expires)| match ban::parse_entry(&network) {
+                Some(parsed) => Some(parsed.with_expiry(expires)),
+                None => {
+                    warn!("Skipping invalid ban in database: {}", network);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+impl TorrentBackend for Sqlite {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn load_torrents(&self) -> Result<TorrentRecords, String> {
+        let torrents = self.select_torrents(i64::MIN).map_err(|e| e.to_string())?;
+
+        Ok(torrents
+            .into_iter()
+            .map(|torrent| (torrent.info_hash.clone(), torrent))
+            .collect())
+    }
+
+    fn load_changed_since(&self, since: u64) -> Result<Vec<Torrent>, String> {
+        let since = i64::try_from(since).unwrap_or(i64::MAX);
+        self.select_torrents(since).map_err(|e| e.to_string())
+    }
+
+    fn load_info_hashes(&self) -> Result<Vec<String>, String> {
+        self.select_info_hashes().map_err(|e| e.to_string())
+    }
+
+    fn flush_torrents(&self, torrents: &[Torrent]) -> Result<(), String> {
+        self.update_torrents(torrents).map_err(|e| e.to_string())
+    }
+
+    fn load_bans(&self) -> Result<Vec<Ban>, String> {
+        self.select_bans().map_err(|e| e.to_string())
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        self.conn
+            .lock()
+            .query_row("SELECT 1", [], |row| row.get::<_, i64>(0))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn migrations(&self) -> &'static [&'static str] {
+        MIGRATIONS
+    }
+
+    fn schema_version(&self) -> Result<u32, String> {
+        self.select_schema_version().map_err(|e| e.to_string())
+    }
+
+    fn is_blank(&self) -> Result<bool, String> {
+        self.select_blank().map_err(|e| e.to_string())
+    }
+
+    fn apply_migration(&self, version: u32, migration: &str) -> Result<(), String> {
+        self.run_migration(version, migration)
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::IpAddr;
+
+    use crate::storage::migrations;
+
+    fn storage(path: &str) -> config::Storage {
+        config::Storage {
+            backend: "sqlite".to_string(),
+            path: path.to_string(),
+            ..config::Storage::default()
+        }
+    }
+
+    fn open(path: &str) -> Sqlite {
+        let backend = Sqlite::open(&storage(path)).unwrap();
+        migrations::migrate(&backend).unwrap();
+        backend
+    }
+
+    #[test]
+    fn sqlite_round_trip() {
+        let path = std::env::temp_dir().join(format!("tyto-test-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        {
+            let backend = Sqlite::open(&storage(path)).unwrap();
+            assert_eq!(backend.schema_version().unwrap(), 0);
+            assert_eq!(migrations::migrate(&backend).unwrap(), (0, 2));
+            assert!(backend.health_check().is_ok());
+            assert!(backend.load_torrents().unwrap().is_empty());
+
+            // Torrents are only ever added to the database from outside
+            backend
+                .conn
+                .lock()
+                .execute_batch(
+                    "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
+                        VALUES ('A1B2C3D4E5F6G7H8I9J0', 0, 0, 0, 0)",
+                )
+                .unwrap();
+
+            let torrent = Torrent::new("A1B2C3D4E5F6G7H8I9J0".to_string(), 1, 2, 3, 4);
+            let deleted = Torrent::new("B2C3D4E5F6G7H8I9J0K1".to_string(), 1, 2, 3, 4);
+            backend.flush_torrents(&[torrent.clone(), deleted]).unwrap();
+
+            let mut updated = torrent;
+            updated.complete = 5;
+            backend.flush_torrents(&[updated]).unwrap();
+        }
+
+        // The schema is only created once and the data survives reopening
+        let backend = open(path);
+        assert_eq!(migrations::migrate(&backend).unwrap(), (2, 2));
+        let torrents = backend.load_torrents().unwrap();
+        assert_eq!(torrents.len(), 1);
+        assert_eq!(torrents["A1B2C3D4E5F6G7H8I9J0"].complete, 5);
+        assert_eq!(torrents["A1B2C3D4E5F6G7H8I9J0"].balance, 4);
+
+        assert_eq!(backend.load_changed_since(0).unwrap().len(), 1);
+        assert!(backend.load_changed_since(u64::MAX).unwrap().is_empty());
+        assert_eq!(
+            backend.load_info_hashes().unwrap(),
+            vec!["A1B2C3D4E5F6G7H8I9J0".to_string()]
+        );
+
+        drop(backend);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sqlite_external_updates() {
+        let backend = open(":memory:");
+        backend
+            .conn
+            .lock()
+            .execute_batch(
+                "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance, updated_at)
+                    VALUES ('A1B2C3D4E5F6G7H8I9J0', 0, 0, 0, 0, 0)",
+            )
+            .unwrap();
+
+        let since = ban::now();
+        assert!(backend.load_changed_since(since).unwrap().is_empty());
+
+        // An operator edits the row by hand
+        backend
+            .conn
+            .lock()
+            .execute_batch(
+                "UPDATE torrents SET complete = 7 WHERE info_hash = 'A1B2C3D4E5F6G7H8I9J0'",
+            )
+            .unwrap();
+
+        let changed = backend.load_changed_since(since).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].complete, 7);
+    }
+
+    #[test]
+    fn sqlite_bans() {
+        let backend = open(":memory:");
+        backend
+            .conn
+            .lock()
+            .execute_batch(
+                "INSERT INTO bans (network, expires_at) VALUES
+                    ('203.0.113.0/24', NULL),
+                    ('198.51.100.1', 1),
+                    ('not an address', NULL)",
+            )
+            .unwrap();
+
+        let bans = backend.load_bans().unwrap();
+        assert_eq!(bans.len(), 1);
+
+        let list = ban::BanList::new();
+        list.replace(bans);
+        assert!(list.is_banned("203.0.113.9".parse::<IpAddr>().unwrap()));
+    }
+}